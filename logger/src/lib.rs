@@ -1,12 +1,13 @@
 use libc;
 use serde::{Deserialize, Serialize};
 use std::{
+    ffi::CString,
     fs::File,
     io::{self, Write},
     path::PathBuf,
     sync::mpsc::{self, Receiver, Sender},
     thread,
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 
 #[macro_export]
@@ -33,21 +34,44 @@ const DAYS_IN_ERA: u64 = 146097;
 
 // This works since:
 // https://doc.rust-lang.org/stable/std/cmp/trait.PartialOrd.html#derivable
-#[derive(Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize, Debug, Clone)]
+#[derive(Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize, Debug, Clone, Default)]
 pub enum LogLevel {
     Error,
     Warning,
+    #[default]
     Info,
 }
 
+impl LogLevel {
+    pub fn posix_level(&self) -> libc::c_int {
+        match self {
+            LogLevel::Info => libc::LOG_INFO,
+            LogLevel::Warning => libc::LOG_WARNING,
+            LogLevel::Error => libc::LOG_ERR,
+        }
+    }
+}
+
 struct Log {
     level: LogLevel,
     msg: String,
 }
 
+// Where and what the log loop writes
+struct Output {
+    level: LogLevel,
+    file: Option<File>,
+    syslog: bool,
+}
+
+enum Message {
+    Log(Log),
+    Reconfigure(Output),
+}
+
 #[derive(Clone)]
 pub struct Logger {
-    tx: Sender<Log>,
+    tx: Sender<Message>,
     syslog: bool,
 }
 
@@ -57,7 +81,7 @@ impl Logger {
     /// Besides that, if a valid logs_path is given logs are also written to a file. And if
     /// bool variable syslog is true logs are also reported to syslog.
     pub fn new(level: LogLevel, logs_path: Option<PathBuf>, syslog: bool) -> io::Result<Self> {
-        let mut file = None;
+        let output = open_output(level, logs_path, syslog)?;
 
         if syslog {
             unsafe {
@@ -65,50 +89,99 @@ impl Logger {
             }
         }
 
-        if let Some(logs_path) = logs_path {
-            file = Some(File::options().create(true).append(true).open(logs_path)?);
-        }
-
         let (tx, rx) = mpsc::channel();
 
         thread::spawn(move || {
-            log_loop(rx, level, file, syslog);
+            log_loop(rx, output);
         });
 
         Ok(Logger { tx, syslog })
     }
 
+    /// Change the level, the logs file and syslog of this logger and all its
+    /// clones. The new file is opened here, on error nothing changes
+    pub fn reconfigure(
+        &self,
+        level: LogLevel,
+        logs_path: Option<PathBuf>,
+        syslog: bool,
+    ) -> io::Result<()> {
+        let output = open_output(level, logs_path, syslog)?;
+
+        self.tx
+            .send(Message::Reconfigure(output))
+            .map_err(|err| io::Error::other(format!("Sending to logger channel: {err}")))
+    }
+
     pub fn send(&self, level: LogLevel, msg: String) {
         self.tx
-            .send(Log { level, msg })
+            .send(Message::Log(Log { level, msg }))
             .inspect_err(|err| eprintln!("Error: Sending to logger channel: {err}"))
             .ok();
     }
 }
 
-fn log_loop(rx: Receiver<Log>, level: LogLevel, mut file: Option<File>, syslog: bool) {
-    for log in rx {
+fn open_output(level: LogLevel, logs_path: Option<PathBuf>, syslog: bool) -> io::Result<Output> {
+    let file = match logs_path {
+        Some(logs_path) => Some(File::options().create(true).append(true).open(logs_path)?),
+        None => None,
+    };
+
+    Ok(Output {
+        level,
+        file,
+        syslog,
+    })
+}
+
+fn log_loop(rx: Receiver<Message>, mut output: Output) {
+    for message in rx {
+        let log = match message {
+            Message::Log(log) => log,
+            Message::Reconfigure(new_output) => {
+                if new_output.syslog && !output.syslog {
+                    unsafe {
+                        libc::openlog(c"taskmaker".as_ptr(), libc::LOG_CONS, libc::LOG_USER);
+                    }
+                } else if !new_output.syslog && output.syslog {
+                    unsafe {
+                        libc::closelog();
+                    }
+                }
+                output = new_output;
+                continue;
+            }
+        };
+        let Output {
+            level,
+            file,
+            syslog,
+        } = &mut output;
         let timestamp = timestamp();
 
-        let (prefix, posix_level) = match level {
-            LogLevel::Info => ("[INFO]", libc::LOG_INFO),
-            LogLevel::Warning => ("[WARN]", libc::LOG_WARNING),
-            LogLevel::Error => ("[ERROR]", libc::LOG_ERR),
+        let prefix = match log.level {
+            LogLevel::Info => "[INFO]",
+            LogLevel::Warning => "[WARN]",
+            LogLevel::Error => "[ERROR]",
         };
 
-        if syslog {
+        if *syslog {
+            // Messages may carry job output, so never use them as the format
+            // string and strip the NULs C would stop at
+            let msg = CString::new(log.msg.replace('\0', "")).unwrap_or_default();
+
             // In syslog we always log no matter the level
             unsafe {
-                libc::syslog(posix_level, log.msg.as_ptr() as *const libc::c_char);
+                libc::syslog(log.level.posix_level(), c"%s".as_ptr(), msg.as_ptr());
             }
         }
 
         // Only log levels with higher or equal severity as configured
-        if log.level <= level {
+        if log.level <= *level {
             let log_msg = format!("{} {}: {}", timestamp, prefix, log.msg);
             eprintln!("{log_msg}");
 
-            if let Some(file) = &mut file {
+            if let Some(file) = file {
                 writeln!(file, "{log_msg}")
                     .inspect_err(|err| eprintln!("Error: {err}"))
                     .ok();
@@ -134,10 +207,94 @@ pub fn timestamp() -> String {
         return "[Wrong System Time]".to_string();
     };
 
-    let Some(days) = { now.as_secs() / SECS_IN_DAY }.checked_add(719468) else {
+    let Some((year, month, day, hour, minute, second)) = civil_time(now.as_secs()) else {
+        return "[Time Overflow]".to_string();
+    };
+
+    format!(
+        "[{}/{}/{} {}:{}:{} UTC]",
+        day, month, year, hour, minute, second
+    )
+}
+
+/// Same clock as `timestamp` but formatted as RFC 3339 with milliseconds,
+/// e.g. 2025-01-31T13:04:05.123Z
+pub fn rfc3339_timestamp() -> String {
+    rfc3339(SystemTime::now())
+}
+
+pub fn rfc3339(time: SystemTime) -> String {
+    let Ok(now) = time.duration_since(SystemTime::UNIX_EPOCH) else {
+        return "[Wrong System Time]".to_string();
+    };
+
+    let Some((year, month, day, hour, minute, second)) = civil_time(now.as_secs()) else {
         return "[Time Overflow]".to_string();
     };
 
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        now.subsec_millis()
+    )
+}
+
+/// Parses the timestamps written by `rfc3339`, the time and the fraction are
+/// optional: 2025-01-31, 2025-01-31T13:04:05Z and 2025-01-31T13:04:05.123Z
+pub fn parse_rfc3339(text: &str) -> Option<SystemTime> {
+    let (date, time) = text.split_once('T').unwrap_or((text, "00:00:00Z"));
+
+    let mut date = date.splitn(3, '-').map(|n| n.parse::<u64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || year < 1970 {
+        return None;
+    }
+
+    let time = time.strip_suffix('Z')?;
+    let (time, millis) = match time.split_once('.') {
+        Some((time, fraction)) => {
+            if fraction.is_empty() || !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+                return None;
+            }
+            // Only ASCII digits, so the first three are bytes
+            let millis = format!("{:0<3}", &fraction[..fraction.len().min(3)]);
+            (time, millis.parse::<u64>().ok()?)
+        }
+        None => (time, 0),
+    };
+    let mut time = time.splitn(3, ':').map(|n| n.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    // Inverse of civil_time, a year too big for the clock is None
+    let year = year - (month <= 2) as u64;
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era
+        .checked_mul(DAYS_IN_ERA)?
+        .checked_add(day_of_era)?
+        .checked_sub(719468)?;
+
+    let secs = days
+        .checked_mul(SECS_IN_DAY)?
+        .checked_add(hour * 3600 + minute * 60 + second)?;
+    let millis = secs.checked_mul(1000)?.checked_add(millis)?;
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_millis(millis))
+}
+
+// Seconds since epoch to (year, month, day, hour, minute, second) in UTC
+fn civil_time(secs: u64) -> Option<(u64, u64, u64, u64, u64, u64)> {
+    let days = { secs / SECS_IN_DAY }.checked_add(719468)?;
+
     // day/month/year in civil time
     let era = days / DAYS_IN_ERA;
     let day_of_era = days - era * DAYS_IN_ERA;
@@ -154,18 +311,61 @@ pub fn timestamp() -> String {
     };
 
     // hour/minute/second in UTC
-    let seconds_today = now.as_secs() % SECS_IN_DAY;
+    let seconds_today = secs % SECS_IN_DAY;
     let hour = seconds_today / 3600;
     let minute = (seconds_today - (hour * 3600)) / 60;
     let second = seconds_today - (hour * 3600) - (minute * 60);
 
-    format!(
-        "[{}/{}/{} {}:{}:{} UTC]",
-        day,
-        month,
+    Some((
         year + (month <= 2) as u64,
+        month,
+        day,
         hour,
         minute,
-        second
-    )
+        second,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rfc3339_round_trip() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_millis(1738328645123);
+        assert_eq!(parse_rfc3339(&rfc3339(time)), Some(time));
+        assert_eq!(
+            parse_rfc3339("2025-01-31"),
+            parse_rfc3339("2025-01-31T00:00:00Z")
+        );
+        assert_eq!(
+            parse_rfc3339("1970-01-01T00:00:00.5Z"),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_millis(500))
+        );
+        assert_eq!(
+            parse_rfc3339("1970-01-01T00:00:00.123456Z"),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_millis(123))
+        );
+    }
+
+    #[test]
+    fn parse_rfc3339_non_ascii_fraction() {
+        assert_eq!(parse_rfc3339("2025-01-31T00:00:00.ééZ"), None);
+        assert_eq!(parse_rfc3339("2025-01-31T00:00:00.1éZ"), None);
+        assert_eq!(parse_rfc3339("2025-01-31T00:00:00.Z"), None);
+    }
+
+    #[test]
+    fn parse_rfc3339_overflow() {
+        assert_eq!(parse_rfc3339("99999999999999999-01-01"), None);
+        assert_eq!(parse_rfc3339("18446744073709551615-12-31T23:59:59Z"), None);
+    }
+
+    #[test]
+    fn parse_rfc3339_invalid() {
+        assert_eq!(parse_rfc3339("1969-12-31"), None);
+        assert_eq!(parse_rfc3339("2025-13-01"), None);
+        assert_eq!(parse_rfc3339("2025-01-31T24:00:00Z"), None);
+        assert_eq!(parse_rfc3339("2025-01-31T00:00:00"), None);
+    }
 }
@@ -3,19 +3,29 @@ use std::{
     path::{Path, PathBuf},
 };
 
-// TODO: Dummy implementation, only parsing "-f" flag, extend this if
-// needed more flags appart from "-f"
+// TODO: Dummy implementation, only parsing "-f" flag and the first other
+// argument. Flags starting with "--" are left for has_flag
 pub fn parse_config_path() -> (Option<PathBuf>, Option<String>) {
+    let mut path = None;
+    let mut other = None;
+
     let mut args = env::args().skip(1);
     while let Some(s) = args.next() {
         if s == "-f" {
-            break;
-        } else {
-            return (None, Some(s));
+            path = args.next().map(PathBuf::from);
+        } else if !s.starts_with("--") && other.is_none() {
+            other = Some(s);
         }
     }
 
-    (args.next().map(|e| PathBuf::from(e)), args.next())
+    (path, other)
+}
+
+// Whether "--name" was given
+pub fn has_flag(name: &str) -> bool {
+    env::args()
+        .skip(1)
+        .any(|arg| arg.strip_prefix("--") == Some(name))
 }
 
 // Only needed for non-shell inputs
@@ -29,6 +39,26 @@ pub fn expand_home_dir(path: &Path) -> PathBuf {
     PathBuf::from(path)
 }
 
+// Shell style match of a file name: * is any run of characters and ? any
+// single one
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    fn matches(pattern: &[char], name: &[char]) -> bool {
+        match (pattern.first(), name.first()) {
+            (None, None) => true,
+            (Some('*'), _) => {
+                matches(&pattern[1..], name) || (!name.is_empty() && matches(pattern, &name[1..]))
+            }
+            (Some('?'), Some(_)) => matches(&pattern[1..], &name[1..]),
+            (Some(p), Some(n)) if p == n => matches(&pattern[1..], &name[1..]),
+            _ => false,
+        }
+    }
+
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    matches(&pattern, &name)
+}
+
 pub fn walk_dir<F>(path: PathBuf, f: &mut F) -> Result<(), io::Error>
 where
     F: FnMut(PathBuf) -> Result<(), io::Error>,
@@ -0,0 +1,209 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+use taskmeister::dir_utils;
+
+use crate::service::{self, Service, ServiceSources};
+
+/// Something wrong in a service file, with where it is when known
+pub struct Problem {
+    pub file: PathBuf,
+    pub position: Option<(usize, usize)>, // Line and column, from 1
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.position {
+            Some((line, column)) => write!(
+                f,
+                "{}:{line}:{column}: {}",
+                self.file.display(),
+                self.message
+            ),
+            None => write!(f, "{}: {}", self.file.display(), self.message),
+        }
+    }
+}
+
+impl Problem {
+    /// The deserialization error of a file, placed where the parser stopped
+    pub fn from_toml(file: &Path, text: &str, err: &toml::de::Error) -> Problem {
+        Problem {
+            file: file.to_path_buf(),
+            position: err.span().map(|span| position(text, span.start)),
+            message: err.message().trim().to_string(),
+        }
+    }
+
+    /// An error of the service merged with its templates, the value may come
+    /// from a template so the key is only found when set in the file
+    pub fn from_value(file: &Path, text: &str, err: &toml::de::Error) -> Problem {
+        // The key path is the last line of the error: in `output.max_size`
+        let path = err.to_string().lines().last().and_then(|line| {
+            line.strip_prefix("in `")?
+                .strip_suffix('`')
+                .map(String::from)
+        });
+
+        match path {
+            Some(path) => Problem {
+                file: file.to_path_buf(),
+                position: key_path_position(text, &path),
+                message: format!("{path}: {}", err.message().trim()),
+            },
+            None => Problem {
+                file: file.to_path_buf(),
+                position: None,
+                message: err.message().trim().to_string(),
+            },
+        }
+    }
+
+    pub fn at_key(file: &Path, text: &str, key: &str, message: String) -> Problem {
+        Problem {
+            file: file.to_path_buf(),
+            position: key_position(text, key),
+            message,
+        }
+    }
+}
+
+/// Checks every service file under the include paths, without stopping at
+/// the first bad one. Returns the files read and the problems found
+pub fn check_services(sources: &ServiceSources) -> (usize, Vec<Problem>) {
+    let mut files = 0;
+    let mut problems = Vec::new();
+    let mut aliases: HashMap<String, PathBuf> = HashMap::new();
+
+    for path in &sources.paths {
+        let path = dir_utils::expand_home_dir(path);
+
+        if !path.exists() {
+            problems.push(Problem {
+                file: path,
+                position: None,
+                message: "Path not found".to_string(),
+            });
+            continue;
+        }
+
+        let service_files = match service::service_files(&path, &sources.patterns) {
+            Ok(service_files) => service_files,
+            Err(err) => {
+                problems.push(Problem {
+                    file: path,
+                    position: None,
+                    message: err.to_string(),
+                });
+                continue;
+            }
+        };
+
+        for file in service_files {
+            files += 1;
+            let text = match fs::read_to_string(&file) {
+                Ok(text) => text,
+                Err(err) => {
+                    problems.push(Problem {
+                        file,
+                        position: None,
+                        message: err.to_string(),
+                    });
+                    continue;
+                }
+            };
+
+            match service::parse_services(&file, &text, sources) {
+                Ok(services) => {
+                    for service in &services {
+                        check_service(service, &file, &text, &mut aliases, &mut problems);
+                    }
+                }
+                Err(problem) => problems.push(problem),
+            }
+        }
+    }
+
+    (files, problems)
+}
+
+fn check_service(
+    service: &Service,
+    file: &Path,
+    text: &str,
+    aliases: &mut HashMap<String, PathBuf>,
+    problems: &mut Vec<Problem>,
+) {
+    let instances = match service.instances() {
+        Ok(instances) => instances,
+        Err((key, message)) => {
+            problems.push(Problem::at_key(file, text, key, message));
+            return;
+        }
+    };
+
+    // Checked on the first job, with its variables expanded
+    for (key, message) in instances.first().unwrap_or(service).problems() {
+        problems.push(Problem::at_key(file, text, key, message));
+    }
+
+    // The jobs of numprocs are alias, alias.1 ... and may clash with other aliases
+    for instance in &instances {
+        if let Some(other) = aliases.insert(instance.alias.clone(), file.to_path_buf()) {
+            problems.push(Problem::at_key(
+                file,
+                text,
+                "alias",
+                format!(
+                    "Alias {} also defined in {}",
+                    instance.alias,
+                    other.display()
+                ),
+            ));
+        }
+    }
+}
+
+// Line and column of a byte offset
+fn position(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+
+    (line, column)
+}
+
+// Where a key is set, the first line starting with it, or its [table] header
+fn key_position(text: &str, key: &str) -> Option<(usize, usize)> {
+    let header = format!("[{key}]");
+    key_position_from(text, key, 0).or_else(|| {
+        text.lines().enumerate().find_map(|(i, line)| {
+            let trimmed = line.trim_start();
+            (trimmed.trim_end() == header).then_some((i + 1, line.len() - trimmed.len() + 1))
+        })
+    })
+}
+
+// Same for a dotted path, the last key is looked for after its [table] header
+fn key_path_position(text: &str, path: &str) -> Option<(usize, usize)> {
+    let Some((table, key)) = path.rsplit_once('.') else {
+        return key_position(text, path);
+    };
+    let header = format!("[{table}]");
+    let start = text.lines().position(|line| line.trim() == header)?;
+
+    key_position_from(text, key, start)
+}
+
+fn key_position_from(text: &str, key: &str, start: usize) -> Option<(usize, usize)> {
+    text.lines().enumerate().skip(start).find_map(|(i, line)| {
+        let trimmed = line.trim_start();
+        let rest = trimmed.strip_prefix(key)?;
+        rest.trim_start()
+            .starts_with('=')
+            .then_some((i + 1, line.len() - trimmed.len() + 1))
+    })
+}
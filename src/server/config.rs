@@ -1,6 +1,7 @@
 use logger::LogLevel;
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     error::Error,
     fs::{self, File},
     io::Write,
@@ -9,6 +10,11 @@ use std::{
 };
 use taskmeister::dir_utils;
 
+use crate::{
+    history::HistoryConfig, io_router::BackpressurePolicy, journal::JournalConfig,
+    service::ServiceSources,
+};
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     #[serde(skip)]
@@ -17,13 +23,32 @@ pub struct Config {
     pub logs: Option<PathBuf>,
     pub syslog: bool,
     pub log_level: LogLevel,
-    include: Include,
+    pub include: Include,
+    // Under every service, then the template it extends and its own file
+    #[serde(default)]
+    pub defaults: toml::Table,
+    #[serde(default)]
+    pub templates: HashMap<String, toml::Table>,
     pub start: Start,
+    #[serde(default)]
+    pub backpressure: BackpressurePolicy,
+    pub journal: Option<JournalConfig>,
+    pub history: Option<HistoryConfig>,
+    pub metrics: Option<SocketAddrV4>, // Address of the HTTP listener serving /metrics
+    #[serde(default)]
+    pub watch_config: bool, // Reload when this file or the include paths change
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Include {
     pub paths: Vec<PathBuf>,
+    // Only the files matching one of them are read from the paths
+    #[serde(default = "default_patterns")]
+    pub patterns: Vec<String>,
+}
+
+fn default_patterns() -> Vec<String> {
+    vec!["*.toml".to_string()]
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -61,10 +86,20 @@ impl Config {
                 logs: None,
                 syslog: false,
                 log_level: LogLevel::Info,
-                include: Include { paths: Vec::new() },
+                include: Include {
+                    paths: Vec::new(),
+                    patterns: default_patterns(),
+                },
+                defaults: toml::Table::new(),
+                templates: HashMap::new(),
                 start: Start {
                     services: Vec::new(),
                 },
+                backpressure: BackpressurePolicy::default(),
+                journal: None,
+                history: None,
+                metrics: None,
+                watch_config: false,
             };
 
             File::create(&c.config_path)?.write(toml::to_string(&c)?.as_bytes())?;
@@ -77,7 +112,25 @@ impl Config {
             ..toml::from_str(&fs::read_to_string(&config_file)?)?
         })
     }
+    /// Reads again the file this config was loaded from
+    pub fn reload(&self) -> Result<Config, Box<dyn Error>> {
+        Config::load(Some(self.config_path.clone()))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.config_path
+    }
+
     pub fn get_includes(&self) -> &Vec<PathBuf> {
         &self.include.paths
     }
+
+    pub fn service_sources(&self) -> ServiceSources {
+        ServiceSources {
+            paths: self.include.paths.clone(),
+            patterns: self.include.patterns.clone(),
+            defaults: self.defaults.clone(),
+            templates: self.templates.clone(),
+        }
+    }
 }
@@ -0,0 +1,200 @@
+use logger::{LogLevel, Logger};
+use std::{
+    collections::HashMap,
+    ffi::{CString, OsStr},
+    fs, io, mem,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+    thread,
+    time::Duration,
+};
+use taskmeister::dir_utils;
+
+use crate::{SIGHUP_FLAG, config::Config};
+
+// Changes closer than this are one burst, the reload waits for the last one
+const DEBOUNCE_MS: i32 = 500;
+// Wait after an error, to not spin on a broken watch
+const RETRY_PERIOD: Duration = Duration::from_secs(1);
+const WATCH_MASK: u32 = libc::IN_CLOSE_WRITE
+    | libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO;
+
+// Directories are watched instead of files since editors and config
+// management replace files by renaming over them. None watches everything
+// in the directory, Some only those names
+type Watches = HashMap<PathBuf, Option<Vec<PathBuf>>>;
+
+/// Watches server.toml and the include paths with inotify, a burst of changes
+/// triggers one reload through the same path as SIGHUP
+pub fn watch(config: &Config, logger: Logger) -> Result<(), io::Error> {
+    let mut watches = config_watches(config);
+    let config_path = config.path().to_path_buf();
+
+    // Fail on start if the watches can not be set, later it is only logged
+    let mut inotify = Inotify::new(&watches)?;
+
+    thread::spawn(move || {
+        loop {
+            match inotify.wait_changes(&watches) {
+                Ok(()) => {
+                    logger::info!(logger, "Config watch: Changes found, reloading");
+                    SIGHUP_FLAG.store(true, Ordering::SeqCst);
+                }
+                Err(err) => {
+                    logger::error!(logger, "Config watch: {err}");
+                    thread::sleep(RETRY_PERIOD);
+                }
+            }
+
+            // The include paths may have changed, and new directories appeared
+            if let Ok(config) = Config::load(Some(config_path.clone())) {
+                watches = config_watches(&config);
+            }
+            match Inotify::new(&watches) {
+                Ok(new_inotify) => inotify = new_inotify,
+                Err(err) => logger::error!(logger, "Config watch: {err}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn config_watches(config: &Config) -> Watches {
+    let mut watches = Watches::new();
+
+    watch_file(&mut watches, config.path());
+    for path in config.get_includes() {
+        let path = dir_utils::expand_home_dir(path);
+        if path.is_dir() {
+            watch_dir(&mut watches, path);
+        } else {
+            watch_file(&mut watches, &path);
+        }
+    }
+
+    watches
+}
+
+fn watch_file(watches: &mut Watches, path: &Path) {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return;
+    };
+    let dir = match dir.as_os_str().is_empty() {
+        true => PathBuf::from("."),
+        false => dir.to_path_buf(),
+    };
+
+    if let Some(names) = watches.entry(dir).or_insert_with(|| Some(Vec::new())) {
+        names.push(PathBuf::from(name));
+    }
+}
+
+// Every directory below, services are loaded recursively
+fn watch_dir(watches: &mut Watches, dir: PathBuf) {
+    if let Ok(entries) = fs::read_dir(&dir) {
+        for entry in entries.flatten() {
+            if entry.path().is_dir() {
+                watch_dir(watches, entry.path());
+            }
+        }
+    }
+    watches.insert(dir, None);
+}
+
+struct Inotify {
+    fd: libc::c_int,
+    dirs: HashMap<libc::c_int, PathBuf>, // Watch descriptor to directory
+}
+
+impl Inotify {
+    fn new(watches: &Watches) -> Result<Inotify, io::Error> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut inotify = Inotify {
+            fd,
+            dirs: HashMap::new(),
+        };
+
+        for dir in watches.keys() {
+            let c_dir = CString::new(dir.as_os_str().as_bytes())
+                .map_err(|err| io::Error::other(format!("{}: {err}", dir.display())))?;
+            let wd = unsafe { libc::inotify_add_watch(fd, c_dir.as_ptr(), WATCH_MASK) };
+            if wd < 0 {
+                let err = io::Error::last_os_error();
+                return Err(io::Error::other(format!("{}: {err}", dir.display())));
+            }
+            inotify.dirs.insert(wd, dir.clone());
+        }
+
+        Ok(inotify)
+    }
+
+    // Blocks until a watched file changes and no other change follows within
+    // the debounce time
+    fn wait_changes(&self, watches: &Watches) -> Result<(), io::Error> {
+        while !self.read_changes(watches)? {}
+
+        let mut poll_fd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        while unsafe { libc::poll(&mut poll_fd, 1, DEBOUNCE_MS) } > 0 {
+            self.read_changes(watches)?;
+        }
+
+        Ok(())
+    }
+
+    // Reads the pending events, true if any is about a watched file
+    fn read_changes(&self, watches: &Watches) -> Result<bool, io::Error> {
+        let mut buffer = [0u8; 4096];
+        let read = unsafe { libc::read(self.fd, buffer.as_mut_ptr().cast(), buffer.len()) };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let header = mem::size_of::<libc::inotify_event>();
+        let mut offset = 0;
+        let mut changed = false;
+
+        while offset + header <= read as usize {
+            let event: libc::inotify_event =
+                unsafe { std::ptr::read_unaligned(buffer[offset..].as_ptr().cast()) };
+            let name_bytes = &buffer[offset + header..offset + header + event.len as usize];
+            let name = Path::new(OsStr::from_bytes(
+                name_bytes
+                    .split(|byte| *byte == 0)
+                    .next()
+                    .unwrap_or_default(),
+            ));
+
+            changed |= self
+                .dirs
+                .get(&event.wd)
+                .is_some_and(|dir| match watches.get(dir) {
+                    Some(Some(names)) => names.iter().any(|watched| watched == name),
+                    Some(None) => true,
+                    None => false,
+                });
+            offset += header + event.len as usize;
+        }
+
+        Ok(changed)
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
@@ -3,17 +3,183 @@
 // orchestrator depeendencies.use std::time::Duration;
 
 use crate::{
-    io_router::RouterRequest, jobs::JobStatus, orchestrate::Orchestrator, service::RestartOptions,
+    history::{RunTrigger, RunUsage},
+    hooks::HookPoint,
+    io_router::RouterRequest,
+    jobs::{ExitReason, JobStatus, PendingHook},
+    orchestrate::{Orchestrator, OrchestratorError},
+    service::RestartOptions,
 };
 use logger::LogLevel;
-use std::time::Duration;
+use serde::Serialize;
+use std::{io, sync::mpsc::Sender};
+use taskmeister::ResponsePart;
 
 pub struct JobEvent {
     pub alias: String,
     pub status: JobStatus,
+    pub usage: Option<RunUsage>, // Only for finished jobs
+}
+
+/// What subscribers get for every change in the state of a job, one JSON
+/// object per stream frame
+#[derive(Serialize)]
+pub struct StateChange {
+    pub time: String, // RFC 3339
+    pub alias: String,
+    pub old: &'static str,
+    pub new: &'static str,
+    pub exit: Option<ExitReason>,
+}
+
+/// A client streaming the state changes of every job, or only of the jobs
+/// of one service
+pub struct Subscriber {
+    pub alias: Option<String>,
+    pub tx: Sender<ResponsePart>,
+}
+
+impl Subscriber {
+    // The alias of a service also matches its instances: alias.1, alias.2 ...
+    fn wants(&self, alias: &str) -> bool {
+        self.alias.as_ref().is_none_or(|filter| {
+            alias == filter
+                || alias
+                    .strip_prefix(filter.as_str())
+                    .and_then(|instance| instance.strip_prefix('.'))
+                    .is_some_and(|instance| instance.parse::<u16>().is_ok())
+        })
+    }
+}
+
+/// A process of the job went over one of the limits of its watchdog
+pub struct WatchdogEvent {
+    pub alias: String,
+    pub pid: u32,
+    pub reason: String,
+}
+
+/// A pre_start or pre_stop hook is done, the start or stop waiting for it
+/// goes on
+pub struct HookEvent {
+    pub alias: String,
+    pub point: HookPoint,
+    pub run: u64, // Told apart from other runs of the job
+    pub result: Result<(), io::Error>,
 }
 
 impl Orchestrator {
+    // Restarts the job through the normal stop path, the next run is recorded
+    // as started by the watchdog
+    pub fn manage_watchdog_event(&mut self, event: WatchdogEvent) {
+        logger::warn!(
+            self.logger,
+            "[Watchdog] [{}] PID {}: {}, restarting",
+            event.alias,
+            event.pid,
+            event.reason
+        );
+
+        if let Err(err) = self.stop_request(&event.alias, false, true) {
+            logger::error!(
+                self.logger,
+                "[Watchdog] [{}] Restarting job: {err}",
+                event.alias
+            );
+            return;
+        }
+
+        if let Some(job) = self.jobs.get_mut(&event.alias) {
+            job.flags.watchdog = true;
+        }
+
+        self.history
+            .watchdog(&event.alias, event.reason)
+            .inspect_err(|err| logger::error!(self.logger, "Saving history: {err}"))
+            .ok();
+    }
+
+    pub fn manage_hook_event(&mut self, event: HookEvent) {
+        // Only the start or stop that ran this hook, it may have been
+        // cancelled or the job be waiting for a later one
+        let Some(job) = self.jobs.get_mut(&event.alias) else {
+            return;
+        };
+        if job.hook_run != event.run {
+            return;
+        }
+        let Some(pending) = job.hook.take() else {
+            return;
+        };
+
+        match pending {
+            PendingHook::Start(trigger, response) => {
+                let result = event
+                    .result
+                    .map_err(|err| OrchestratorError::HookFailed(event.point.name(), err))
+                    .and_then(|_| self.launch_job(&event.alias, trigger));
+
+                if let Err(err) = &result {
+                    logger::error!(self.logger, "[{}] Starting job: {err}", event.alias);
+                }
+                if let Some(response) = response {
+                    response.send(result.into()).ok();
+                }
+            }
+            PendingHook::Stop(paused) => {
+                if let Err(err) = event.result {
+                    logger::warn!(
+                        self.logger,
+                        "[{}] {} hook: {err}",
+                        event.alias,
+                        event.point.name()
+                    );
+                }
+
+                // It may have exited on its own while the hook ran
+                if self.get_job_status(&event.alias) == Some(JobStatus::Stopping)
+                    && let Err(err) = self.signal_stop(&event.alias, paused)
+                {
+                    logger::error!(self.logger, "[{}] Stopping job: {err}", event.alias);
+                }
+            }
+        }
+    }
+
+    pub fn subscribe(&mut self, alias: Option<String>, tx: Sender<ResponsePart>) {
+        self.subscribers.push(Subscriber { alias, tx });
+    }
+
+    // Sends the change to the interested subscribers, the ones that went away
+    // are dropped here
+    pub fn publish(&mut self, alias: &str, old: &JobStatus, new: &JobStatus) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+
+        let change = StateChange {
+            time: logger::rfc3339_timestamp(),
+            alias: alias.to_string(),
+            old: old.name(),
+            new: new.name(),
+            exit: match new {
+                JobStatus::Finished(reason) => Some(reason.clone()),
+                _ => None,
+            },
+        };
+        let Ok(change) = serde_json::to_vec(&change) else {
+            return;
+        };
+
+        self.subscribers.retain(|subscriber| {
+            !subscriber.wants(alias)
+                || subscriber
+                    .tx
+                    .send(ResponsePart::Stream(change.clone()))
+                    .is_ok()
+        });
+    }
+
     pub fn manage_event(&mut self, event: JobEvent) {
         logger::info!(self.logger, "[Event] [{}] {}", event.alias, event.status);
 
@@ -29,20 +195,30 @@ impl Orchestrator {
             JobStatus::Created
             | JobStatus::Starting
             | JobStatus::Running(true)
-            | JobStatus::Stopping => (event.status, false),
+            | JobStatus::Paused
+            | JobStatus::Stopping => (event.status, None),
 
             JobStatus::Running(false) => {
                 if matches!(previous_status, JobStatus::Running(true)) {
                     // If previous status was Running(true) it means it comes
                     // from a timeout which means it is healthy now
                     logger::info!(self.logger, "[{}] Healthy ✅", event.alias);
-                    (previous_status, false)
+                    (previous_status.clone(), None)
+                } else if matches!(previous_status, JobStatus::Stopping | JobStatus::Paused) {
+                    // Still alive after a step of the stop sequence it keeps
+                    // stopping, and a paused job is alive but stays paused
+                    (previous_status.clone(), None)
                 } else {
-                    (event.status, false)
+                    (event.status, None)
                 }
             }
 
-            JobStatus::Finished(exit_code) => 'finished: {
+            JobStatus::Finished(ref reason) => 'finished: {
+                self.history
+                    .finish(&event.alias, reason.clone(), event.usage.clone())
+                    .inspect_err(|err| logger::error!(self.logger, "Saving history: {err}"))
+                    .ok();
+
                 // End the forwarding cleanly if any
                 self.io_router_requests
                     .stop_forwarding(&event.alias)
@@ -57,6 +233,12 @@ impl Orchestrator {
 
                 // If job was stopping just end
                 if previous_status == JobStatus::Stopping {
+                    self.spawn_hook(
+                        &event.alias,
+                        HookPoint::PostStop,
+                        self.hook_context(&event.alias, Some(reason.clone())),
+                    );
+
                     let flags = self.consume_job_flags(&event.alias);
 
                     if flags.remove_service {
@@ -64,40 +246,63 @@ impl Orchestrator {
                     }
 
                     if flags.restart_job {
-                        break 'finished (event.status, true);
+                        let trigger = match flags.watchdog {
+                            true => RunTrigger::Watchdog,
+                            false => RunTrigger::Restart,
+                        };
+                        break 'finished (event.status, Some(trigger));
                     }
 
-                    break 'finished (event.status, false);
+                    break 'finished (event.status, None);
                 }
 
                 // Restart if needed
                 match service.restart {
-                    RestartOptions::Never => (event.status, false),
+                    RestartOptions::Never => {
+                        if !service.validate_exit_code(reason) {
+                            self.spawn_hook(
+                                &event.alias,
+                                HookPoint::OnFailure,
+                                self.hook_context(&event.alias, Some(reason.clone())),
+                            );
+                        }
+                        (event.status, None)
+                    }
 
                     RestartOptions::Always(retries) => 'status: {
                         if let Some(current_retries) = self.inc_job_retries(&event.alias) {
                             // Restrat if we didn't reach the maximum retries
                             if current_retries < retries {
-                                break 'status (event.status, true);
+                                break 'status (event.status, Some(RunTrigger::Retry));
                             }
                         }
                         logger::info!(self.logger, "[{}] Exhausted retries", &event.alias);
-                        (event.status, false)
+                        self.spawn_hook(
+                            &event.alias,
+                            HookPoint::OnFailure,
+                            self.hook_context(&event.alias, Some(reason.clone())),
+                        );
+                        (event.status, None)
                     }
 
                     RestartOptions::Unexpected(retries) => 'status: {
                         if let Some(current_retries) = self.inc_job_retries(&event.alias) {
                             // Restrat if we didn't reach the maximum retries, and the code is not expected
-                            if !service.validate_exit_code(exit_code) {
+                            if !service.validate_exit_code(reason) {
                                 if current_retries < retries {
-                                    break 'status (event.status, true);
+                                    break 'status (event.status, Some(RunTrigger::Retry));
                                 }
                             } else {
-                                break 'status (event.status, false);
+                                break 'status (event.status, None);
                             }
                         }
                         logger::info!(self.logger, "[{}] Exhausted retries", &event.alias);
-                        (event.status, false)
+                        self.spawn_hook(
+                            &event.alias,
+                            HookPoint::OnFailure,
+                            self.hook_context(&event.alias, Some(reason.clone())),
+                        );
+                        (event.status, None)
                     }
                 }
             }
@@ -107,21 +312,31 @@ impl Orchestrator {
                     JobStatus::Running(_) | JobStatus::Starting => {
                         // If it comes from running it means it is healthy now
                         self.remove_watched_timeout(&event.alias);
-                        (JobStatus::Running(true), false)
+                        (JobStatus::Running(true), None)
+                    }
+                    JobStatus::Stopping
+                        if self
+                            .jobs
+                            .get(&event.alias)
+                            .is_some_and(|job| matches!(job.hook, Some(PendingHook::Stop(_)))) =>
+                    {
+                        // The stop sequence starts once the pre_stop hook is done
+                        (JobStatus::Stopping, None)
                     }
                     JobStatus::TimedOut | JobStatus::Stopping => {
                         // If job (not watched job) is in stopping status, it means that
-                        // we previously tried to stop or kill it
-                        if let Err(err) = self.kill_job(
-                            &event.alias,
-                            libc::SIGKILL,
-                            Duration::from_secs(service.stop_wait),
-                        ) {
+                        // the previous step of the stop sequence did not stop it
+                        let step = self.jobs.get_mut(&event.alias).map_or(0, |job| {
+                            job.stop_step += 1;
+                            job.stop_step
+                        });
+                        if let Err(err) = self.stop_job(&event.alias, step) {
                             logger::error!(self.logger, "Kill job: {err}");
                         };
-                        (JobStatus::Stopping, false)
+                        (JobStatus::Stopping, None)
                     }
-                    _ => (JobStatus::TimedOut, false),
+                    JobStatus::Paused => (JobStatus::Paused, None),
+                    _ => (JobStatus::TimedOut, None),
                 }
             }
         };
@@ -130,12 +345,31 @@ impl Orchestrator {
             return;
         };
 
-        job.status = new_status;
+        if let JobStatus::Finished(reason) = &new_status {
+            job.last_exit = Some(reason.clone());
+        }
+        let healthy = new_status == JobStatus::Running(true);
+        self.set_job_status(&event.alias, new_status);
+
+        // Its service was removed by a reload or a scale, once the finish is
+        // published the job goes with it
+        if self.get_services().get(&event.alias).is_none() {
+            self.remove_job(&event.alias);
+            return;
+        }
+
+        if healthy && previous_status != JobStatus::Running(true) {
+            self.spawn_hook(
+                &event.alias,
+                HookPoint::PostStart,
+                self.hook_context(&event.alias, None),
+            );
+        }
 
         // If job needs to be restarted, do it
-        if restart {
-            // If restart is true, the job is in finish, so  this will work
-            if let Err(error) = self.start_request(&event.alias) {
+        if let Some(trigger) = restart {
+            // If restart is set, the job is in finish, so  this will work
+            if let Err(error) = self.start_request(&event.alias, trigger, None) {
                 logger::error!(self.logger, "Restarting job: {error}");
             }
         }
@@ -0,0 +1,266 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    fs::{self, File},
+    io,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+use taskmeister::dir_utils;
+
+use crate::{
+    jobs::ExitReason,
+    journal::{self, JournalConfig},
+};
+
+// Runs kept per job when no history section is configured
+pub const HISTORY_LEN: usize = 20;
+
+/// Where and how many runs of each job are kept. Without it the history
+/// only lives in memory
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HistoryConfig {
+    pub dir: PathBuf,
+    pub runs: usize,
+}
+
+/// What made a job start
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum RunTrigger {
+    Manual,   // Start command or startup
+    Restart,  // Restart command or configuration reload
+    Retry,    // Restart policy after the job finished
+    Watchdog, // Previous run went over a resource limit
+}
+
+/// Resources used by a finished job, as reported by wait4
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RunUsage {
+    pub user_time: Duration,
+    pub system_time: Duration,
+    pub max_rss: u64, // In KiB
+}
+
+impl From<libc::rusage> for RunUsage {
+    fn from(rusage: libc::rusage) -> Self {
+        let to_duration = |time: libc::timeval| {
+            Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+        };
+
+        RunUsage {
+            user_time: to_duration(rusage.ru_utime),
+            system_time: to_duration(rusage.ru_stime),
+            max_rss: rusage.ru_maxrss as u64,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RunRecord {
+    pub run: u64,
+    pub trigger: RunTrigger,
+    pub started: SystemTime,
+    pub ended: Option<SystemTime>,
+    pub exit: Option<ExitReason>,
+    pub usage: Option<RunUsage>,
+    #[serde(default)]
+    pub watchdog: Option<String>, // Why the watchdog stopped this run
+}
+
+impl Display for RunRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "#{} [{:?}] {}",
+            self.run,
+            self.trigger,
+            logger::rfc3339(self.started)
+        )?;
+
+        let Some(ended) = self.ended else {
+            return write!(f, " Running");
+        };
+
+        write!(
+            f,
+            " -> {} ({:.1}s) {}",
+            logger::rfc3339(ended),
+            ended
+                .duration_since(self.started)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            self.exit
+                .as_ref()
+                .map_or("Unknown".to_string(), |exit| exit.to_string()),
+        )?;
+
+        if let Some(usage) = &self.usage {
+            write!(
+                f,
+                " User: {:.2}s Sys: {:.2}s Max RSS: {} KiB",
+                usage.user_time.as_secs_f64(),
+                usage.system_time.as_secs_f64(),
+                usage.max_rss
+            )?;
+        }
+
+        if let Some(reason) = &self.watchdog {
+            write!(f, " Watchdog: {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+pub struct History {
+    config: Option<HistoryConfig>,
+    journal: Option<JournalConfig>, // Also numbers its runs, it may know later ones
+    runs: HashMap<String, VecDeque<RunRecord>>,
+    next: HashMap<String, u64>, // Number of the next run of each job
+}
+
+impl History {
+    pub fn new(config: Option<HistoryConfig>, journal: Option<JournalConfig>) -> History {
+        History {
+            config,
+            journal,
+            runs: HashMap::new(),
+            next: HashMap::new(),
+        }
+    }
+
+    /// Number the next run of the job gets, in the history and the journal.
+    /// Continues from both on disk, the history may only live in memory
+    pub fn next_run(&mut self, alias: &str) -> u64 {
+        if let Some(run) = self.next.get(alias) {
+            return *run;
+        }
+
+        let history = self.load(alias).back().map_or(0, |record| record.run);
+        let journal = self
+            .journal
+            .as_ref()
+            .and_then(|config| journal::last_run(config, alias).ok().flatten())
+            .unwrap_or(0);
+        let run = history.max(journal) + 1;
+
+        self.next.insert(alias.to_string(), run);
+        run
+    }
+
+    /// Records the start of a new run of the job
+    pub fn start(&mut self, alias: &str, trigger: RunTrigger) -> Result<(), io::Error> {
+        let run = self.next_run(alias);
+        self.next.insert(alias.to_string(), run + 1);
+
+        let len = self.len();
+        let runs = self.load(alias);
+
+        if runs.len() >= len {
+            runs.pop_front();
+        }
+        runs.push_back(RunRecord {
+            run,
+            trigger,
+            started: SystemTime::now(),
+            ended: None,
+            exit: None,
+            usage: None,
+            watchdog: None,
+        });
+
+        self.persist(alias)
+    }
+
+    /// Completes the last run of the job
+    pub fn finish(
+        &mut self,
+        alias: &str,
+        exit: ExitReason,
+        usage: Option<RunUsage>,
+    ) -> Result<(), io::Error> {
+        let Some(record) = self.load(alias).back_mut() else {
+            return Ok(());
+        };
+
+        record.ended = Some(SystemTime::now());
+        record.exit = Some(exit);
+        record.usage = usage;
+
+        self.persist(alias)
+    }
+
+    /// Records why the watchdog is stopping the last run of the job
+    pub fn watchdog(&mut self, alias: &str, reason: String) -> Result<(), io::Error> {
+        let Some(record) = self.load(alias).back_mut() else {
+            return Ok(());
+        };
+
+        record.watchdog = Some(reason);
+
+        self.persist(alias)
+    }
+
+    pub fn describe(&mut self, alias: &str) -> String {
+        let runs = self.load(alias);
+        if runs.is_empty() {
+            return "No runs recorded".to_string();
+        }
+
+        let count = |trigger: RunTrigger| {
+            runs.iter()
+                .filter(|record| record.trigger == trigger)
+                .count()
+        };
+
+        runs.iter().fold(
+            format!(
+                "Runs: {} (Manual: {}, Restart: {}, Retry: {}, Watchdog: {})\n",
+                runs.len(),
+                count(RunTrigger::Manual),
+                count(RunTrigger::Restart),
+                count(RunTrigger::Retry),
+                count(RunTrigger::Watchdog),
+            ),
+            |acc, record| acc + &format!("\n{record}"),
+        )
+    }
+
+    fn len(&self) -> usize {
+        self.config
+            .as_ref()
+            .map_or(HISTORY_LEN, |config| config.runs)
+    }
+
+    // Runs of a job, read from disk the first time they are needed
+    fn load(&mut self, alias: &str) -> &mut VecDeque<RunRecord> {
+        let path = self.path(alias);
+
+        self.runs.entry(alias.to_string()).or_insert_with(|| {
+            path.and_then(|path| File::open(path).ok())
+                .and_then(|file| serde_json::from_reader(file).ok())
+                .unwrap_or_default()
+        })
+    }
+
+    fn persist(&self, alias: &str) -> Result<(), io::Error> {
+        let (Some(path), Some(runs)) = (self.path(alias), self.runs.get(alias)) else {
+            return Ok(());
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Write aside and rename, so a crash never leaves half a history
+        let tmp = path.with_extension("json.tmp");
+        serde_json::to_writer(File::create(&tmp)?, runs)?;
+        fs::rename(tmp, path)
+    }
+
+    fn path(&self, alias: &str) -> Option<PathBuf> {
+        self.config
+            .as_ref()
+            .map(|config| dir_utils::expand_home_dir(&config.dir).join(format!("{alias}.json")))
+    }
+}
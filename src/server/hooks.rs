@@ -0,0 +1,154 @@
+use logger::{LogLevel, Logger};
+use std::{
+    io, thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    events::HookEvent,
+    jobs::ExitReason,
+    orchestrate::{OrchestratorMsg, OrchestratorSender},
+    service::{self, Hook, Service},
+};
+
+// Seconds a hook may run when the service does not say
+pub const HOOK_TIMEOUT: u64 = 30;
+// How often a running hook is checked
+const HOOK_POLL: Duration = Duration::from_millis(50);
+
+/// Points of the life of a job where a hook may run
+#[derive(Debug, Clone, Copy)]
+pub enum HookPoint {
+    PreStart,
+    PostStart,
+    PreStop,
+    PostStop,
+    OnFailure,
+}
+
+impl HookPoint {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookPoint::PreStart => "pre_start",
+            HookPoint::PostStart => "post_start",
+            HookPoint::PreStop => "pre_stop",
+            HookPoint::PostStop => "post_stop",
+            HookPoint::OnFailure => "on_failure",
+        }
+    }
+
+    pub fn hook(self, service: &Service) -> Option<&Hook> {
+        match self {
+            HookPoint::PreStart => service.hooks.pre_start.as_ref(),
+            HookPoint::PostStart => service.hooks.post_start.as_ref(),
+            HookPoint::PreStop => service.hooks.pre_stop.as_ref(),
+            HookPoint::PostStop => service.hooks.post_stop.as_ref(),
+            HookPoint::OnFailure => service.hooks.on_failure.as_ref(),
+        }
+    }
+}
+
+/// What the hook is told about the event
+#[derive(Debug, Default)]
+pub struct HookContext {
+    pub retries: u8,
+    pub pid: Option<u32>,
+    pub exit: Option<ExitReason>,
+}
+
+/// Runs the hook of the service for that point, if it has one, and waits
+/// for it. A hook over its timeout is killed and counts as failed
+pub fn run(service: &Service, point: HookPoint, context: &HookContext) -> Result<(), io::Error> {
+    let Some(hook) = point.hook(service) else {
+        return Ok(());
+    };
+
+    let mut cmd = service.helper_command(&hook.cmd)?;
+    cmd.env("TASKMEISTER_HOOK", point.name())
+        .env("TASKMEISTER_ALIAS", &service.alias)
+        .env("TASKMEISTER_RETRIES", context.retries.to_string());
+
+    if let Some(pid) = context.pid {
+        cmd.env("TASKMEISTER_PID", pid.to_string());
+    }
+
+    match &context.exit {
+        Some(ExitReason::Code(code)) => {
+            cmd.env("TASKMEISTER_EXIT_CODE", code.to_string());
+        }
+        Some(ExitReason::Signal { signal, .. }) => {
+            cmd.env(
+                "TASKMEISTER_SIGNAL",
+                service::signal_to_str(*signal).unwrap_or("UNKNOWN"),
+            );
+        }
+        None => (),
+    }
+
+    let mut child = cmd.spawn()?;
+    let timeout = Duration::from_secs(hook.timeout.unwrap_or(HOOK_TIMEOUT));
+    let started = Instant::now();
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if status.success() {
+                return Ok(());
+            }
+            return Err(io::Error::other(format!(
+                "{} exited with {status}",
+                hook.cmd
+            )));
+        }
+
+        if started.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{} timed out after {}s", hook.cmd, timeout.as_secs()),
+            ));
+        }
+
+        thread::sleep(HOOK_POLL);
+    }
+}
+
+/// Same as `run` but in its own thread, for the hooks nothing waits for.
+/// Failures are only logged
+pub fn spawn(service: &Service, point: HookPoint, context: HookContext, logger: Logger) {
+    if point.hook(service).is_none() {
+        return;
+    }
+    let service = service.clone();
+
+    thread::spawn(move || {
+        if let Err(err) = run(&service, point, &context) {
+            logger::warn!(logger, "[{}] {} hook: {err}", service.alias, point.name());
+        }
+    });
+}
+
+/// Same as `run` but in its own thread, for the hooks a start or a stop
+/// waits for. The result goes back to the orchestrator as a hook event
+pub fn spawn_waited(
+    service: &Service,
+    point: HookPoint,
+    run: u64,
+    context: HookContext,
+    events: OrchestratorSender,
+    logger: Logger,
+) {
+    let service = service.clone();
+
+    thread::spawn(move || {
+        let event = OrchestratorMsg::Hook(HookEvent {
+            alias: service.alias.clone(),
+            point,
+            run,
+            result: self::run(&service, point, &context),
+        });
+        if let Err(err) = events.send(event) {
+            logger::error!(logger, "[{}] Sending hook event: {err}", service.alias);
+        }
+    });
+}
@@ -1,45 +1,461 @@
 use std::{
     collections::{HashMap, VecDeque},
-    fs::{File, OpenOptions},
+    fs::{self, File, OpenOptions},
     io::{self, Read, Write},
+    mem,
+    os::unix::net::UnixDatagram,
+    path::PathBuf,
     process::{ChildStderr, ChildStdout},
-    sync::mpsc::{self, Receiver, Sender, SyncSender},
+    sync::{
+        Arc, Mutex,
+        mpsc::{self, Receiver, Sender, SyncSender, TrySendError},
+    },
     thread,
     time::Duration,
 };
 
 use logger::{LogLevel, Logger};
+use serde::{Deserialize, Serialize};
 
-use crate::orchestrate::OrchestratorError;
+use crate::{
+    journal::{Journal, JournalConfig},
+    orchestrate::OrchestratorError,
+    service::{OutputMode, OutputOptions, Service, TimestampFormat},
+};
 
 pub const IO_ROUTER_READ_BUF_LEN: usize = 1024;
 const DEQUE_BUF_LEN: usize = 10;
 const DRAIN_TIMES: usize = 100;
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// Stdout and Stderr bytes read from every job since the server started,
+/// kept by the router and read by the metrics thread without asking it
+pub type IoCounters = Arc<Mutex<HashMap<String, (u64, u64)>>>;
+
+/// What to do with the output of a job when the attached client is not
+/// consuming it fast enough
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Clone)]
+#[serde(tag = "policy", content = "max_bytes")]
+pub enum BackpressurePolicy {
+    #[default]
+    Drop, // Discard chunks until the client catches up
+    Coalesce(usize), // Merge chunks up to max_bytes, discard beyond that
+}
+
+// An attached client. Sending never blocks, so a stalled client can not
+// freeze the routing of the other jobs
+struct Subscriber {
+    tx: SyncSender<Vec<u8>>,
+    pending: Vec<u8>, // Coalesced bytes waiting for room in the channel
+    dropped: usize,   // Bytes discarded since the last drop marker
+}
+
+impl Subscriber {
+    fn new(tx: SyncSender<Vec<u8>>) -> Subscriber {
+        Subscriber {
+            tx,
+            pending: Vec::new(),
+            dropped: 0,
+        }
+    }
+
+    // Deliver the pending bytes and then the drop marker, if there is room
+    fn flush(&mut self) {
+        if !self.pending.is_empty()
+            && let Err(TrySendError::Full(data)) = self.tx.try_send(mem::take(&mut self.pending))
+        {
+            self.pending = data;
+            return;
+        }
+
+        if self.dropped > 0 {
+            let marker = format!("\n[taskmeister: {} bytes dropped]\n", self.dropped);
+            if !matches!(
+                self.tx.try_send(marker.into_bytes()),
+                Err(TrySendError::Full(_))
+            ) {
+                self.dropped = 0;
+            }
+        }
+    }
+
+    fn send(&mut self, data: &[u8], policy: &BackpressurePolicy) {
+        self.flush();
+
+        // Once something has been dropped, keep dropping until the marker is
+        // delivered so the client sees the gap where it happened
+        if self.dropped > 0 {
+            self.dropped += data.len();
+            return;
+        }
+
+        match policy {
+            BackpressurePolicy::Drop => {
+                if let Err(TrySendError::Full(_)) = self.tx.try_send(data.to_vec()) {
+                    self.dropped += data.len();
+                }
+            }
+            BackpressurePolicy::Coalesce(max_bytes) => {
+                if !self.pending.is_empty() {
+                    if self.pending.len() + data.len() <= *max_bytes {
+                        self.pending.extend_from_slice(data);
+                    } else {
+                        self.dropped += data.len();
+                    }
+                } else if let Err(TrySendError::Full(data)) = self.tx.try_send(data.to_vec()) {
+                    if data.len() <= *max_bytes {
+                        self.pending = data;
+                    } else {
+                        self.dropped += data.len();
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Output file of a job, rotated according to the output options of the service
+struct OutputFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    options: OutputOptions,
+}
+
+impl OutputFile {
+    fn open(path: &str, options: &OutputOptions) -> Result<OutputFile, io::Error> {
+        let path = PathBuf::from(path);
+        let mut mode = options.mode.clone();
+
+        if options.rotate_on_restart && path.metadata().is_ok_and(|meta| meta.len() > 0) {
+            rotate_files(&path, options.backups)?;
+            mode = OutputMode::Truncate;
+        }
+
+        let file = open_output(&path, &mode)?;
+
+        Ok(OutputFile {
+            path,
+            size: file.metadata()?.len(),
+            file,
+            options: options.clone(),
+        })
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        if self.options.max_size > 0
+            && self.size > 0
+            && self.size + data.len() as u64 > self.options.max_size
+        {
+            rotate_files(&self.path, self.options.backups)?;
+            self.file = open_output(&self.path, &OutputMode::Truncate)?;
+            self.size = 0;
+        }
+
+        self.file.write_all(data)?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    // Used after an external tool (logrotate) moved the file away. Always
+    // appends, since the new file may have been created by that tool
+    fn reopen(&mut self) -> Result<(), io::Error> {
+        self.file = open_output(&self.path, &OutputMode::Append)?;
+        self.size = self.file.metadata()?.len();
+        Ok(())
+    }
+}
+
+// Splits the captured output in lines, keeping the last incomplete one until
+// the rest arrives. Overlong lines are split so memory stays bounded
+struct LineBuffer {
+    partial: Vec<u8>,
+}
+
+impl LineBuffer {
+    fn new() -> LineBuffer {
+        LineBuffer {
+            partial: Vec::new(),
+        }
+    }
+
+    fn lines(&mut self, data: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        for byte in data {
+            if *byte == b'\n' || self.partial.len() >= IO_ROUTER_READ_BUF_LEN {
+                lines.push(String::from_utf8_lossy(&mem::take(&mut self.partial)).to_string());
+            }
+            if *byte != b'\n' {
+                self.partial.push(*byte);
+            }
+        }
+        lines
+    }
+
+    // The bytes of a last line that never got its newline
+    fn rest(&mut self) -> Option<String> {
+        (!self.partial.is_empty())
+            .then(|| String::from_utf8_lossy(&mem::take(&mut self.partial)).to_string())
+    }
+}
+
+// Where the captured output of a stream ends up
+enum OutputSink {
+    File(OutputFile),
+    Logger {
+        logger: Logger,
+        level: LogLevel,
+        alias: String,
+        lines: LineBuffer,
+    },
+    Syslog {
+        socket: UnixDatagram,
+        priority: libc::c_int,
+        ident: String,
+        lines: LineBuffer,
+    },
+}
+
+impl OutputSink {
+    // Returns None for "null", which discards the output
+    fn open(
+        target: &str,
+        alias: &str,
+        options: &OutputOptions,
+        logger: &Logger,
+    ) -> Result<Option<OutputSink>, io::Error> {
+        Ok(match target {
+            "null" => None,
+            "logger" => Some(OutputSink::Logger {
+                logger: logger.clone(),
+                level: options.log_level.clone(),
+                alias: alias.to_string(),
+                lines: LineBuffer::new(),
+            }),
+            "syslog" => match syslog_socket() {
+                Ok(socket) => Some(OutputSink::Syslog {
+                    socket,
+                    priority: options.syslog_facility.posix_facility()
+                        | options.log_level.posix_level(),
+                    ident: alias.to_string(),
+                    lines: LineBuffer::new(),
+                }),
+                Err(err) => {
+                    logger::warn!(logger, "[{alias}] Syslog: {err}, output sent to the logger");
+                    Some(OutputSink::Logger {
+                        logger: logger.clone(),
+                        level: options.log_level.clone(),
+                        alias: alias.to_string(),
+                        lines: LineBuffer::new(),
+                    })
+                }
+            },
+            path => Some(OutputSink::File(OutputFile::open(path, options)?)),
+        })
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        match self {
+            OutputSink::File(file) => file.write(data),
+            OutputSink::Logger {
+                logger,
+                level,
+                alias,
+                lines,
+            } => {
+                for line in lines.lines(data) {
+                    logger.send(level.clone(), format!("[{alias}] {line}"));
+                }
+                Ok(())
+            }
+            OutputSink::Syslog {
+                socket,
+                priority,
+                ident,
+                lines,
+            } => {
+                // Non blocking: if the syslog daemon can not keep up lines are lost
+                // instead of stopping the router
+                for line in lines.lines(data) {
+                    socket.send(format!("<{priority}>{ident}: {line}").as_bytes())?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn reopen(&mut self) -> Result<(), io::Error> {
+        match self {
+            OutputSink::File(file) => file.reopen(),
+            _ => Ok(()),
+        }
+    }
+
+    // The stream is over, a last line without newline goes out as it is
+    fn finish(&mut self) -> Result<(), io::Error> {
+        match self {
+            OutputSink::File(_) => Ok(()),
+            OutputSink::Logger {
+                logger,
+                level,
+                alias,
+                lines,
+            } => {
+                if let Some(line) = lines.rest() {
+                    logger.send(level.clone(), format!("[{alias}] {line}"));
+                }
+                Ok(())
+            }
+            OutputSink::Syslog {
+                socket,
+                priority,
+                ident,
+                lines,
+            } => {
+                if let Some(line) = lines.rest() {
+                    socket.send(format!("<{priority}>{ident}: {line}").as_bytes())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// A sink that cannot be opened discards the output, the job still runs
+fn open_sink(
+    target: &str,
+    alias: &str,
+    options: &OutputOptions,
+    logger: &Logger,
+) -> Option<OutputSink> {
+    OutputSink::open(target, alias, options, logger).unwrap_or_else(|err| {
+        logger::warn!(
+            logger,
+            "[{alias}] Cannot open {target}: {err}, output discarded"
+        );
+        None
+    })
+}
+
+fn syslog_socket() -> Result<UnixDatagram, io::Error> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(SYSLOG_SOCKET)?;
+    taskmeister::set_fd_flag(&socket, libc::O_NONBLOCK);
+    Ok(socket)
+}
+
+// Prepends the configured prefix to every line of the captured output,
+// remembering between reads whether the next byte starts a new line
+struct LinePrefixer {
+    timestamp: Option<TimestampFormat>,
+    tag: String, // Alias and stream name, fixed for the whole job
+    line_start: bool,
+}
+
+impl LinePrefixer {
+    fn new(alias: &str, stream: &str, options: &OutputOptions) -> Option<LinePrefixer> {
+        if options.timestamp.is_none() && !options.prefix_alias && !options.prefix_stream {
+            return None;
+        }
+
+        let mut tag = String::new();
+        if options.prefix_alias {
+            tag.push_str(&format!("[{alias}] "));
+        }
+        if options.prefix_stream {
+            tag.push_str(&format!("[{stream}] "));
+        }
+
+        Some(LinePrefixer {
+            timestamp: options.timestamp.clone(),
+            tag,
+            line_start: true,
+        })
+    }
+
+    fn apply(&mut self, data: &[u8]) -> Vec<u8> {
+        // Every line in the same read shares the timestamp
+        let prefix = match &self.timestamp {
+            Some(TimestampFormat::Logger) => format!("{} {}", logger::timestamp(), self.tag),
+            Some(TimestampFormat::Rfc3339) => {
+                format!("{} {}", logger::rfc3339_timestamp(), self.tag)
+            }
+            None => self.tag.clone(),
+        };
+
+        let mut prefixed = Vec::with_capacity(data.len() + prefix.len());
+        for byte in data {
+            if self.line_start {
+                prefixed.extend_from_slice(prefix.as_bytes());
+                self.line_start = false;
+            }
+            prefixed.push(*byte);
+            self.line_start = *byte == b'\n';
+        }
+        prefixed
+    }
+}
 
 struct Stdout {
     pipe: ChildStdout,
-    def_stdout: Option<File>,
-    tx: Option<SyncSender<Vec<u8>>>,
+    def_stdout: Option<OutputSink>,
+    tx: Option<Subscriber>,
     buff: VecDeque<Vec<u8>>,
+    prefixer: Option<LinePrefixer>,
+    journal_lines: LineBuffer,
+    bytes: u64, // Read from the pipe since the job started
 }
 
 impl Stdout {
+    // Nothing more comes from the pipe, the last line is not held anymore
+    fn finish(&mut self, journal: &mut Option<Journal>) {
+        let line = self.journal_lines.rest();
+        if let (Some(journal), Some(line)) = (journal, line) {
+            let _ = journal.append("stdout", vec![line]);
+        }
+        if let Some(stdout) = &mut self.def_stdout {
+            let _ = stdout.finish();
+        }
+    }
+
     // Return Ok(false) if we do not want to continue reading
-    fn forward(&mut self, buf: &mut [u8]) -> Result<bool, io::Error> {
+    fn forward(
+        &mut self,
+        buf: &mut [u8],
+        policy: &BackpressurePolicy,
+        journal: &mut Option<Journal>,
+    ) -> Result<bool, io::Error> {
         match self.pipe.read(buf) {
-            Ok(0) => Ok(false),
+            Ok(0) => {
+                self.finish(journal);
+                Ok(false)
+            }
             Ok(bytes) => {
-                // Always push into the ring buffer
-                ring_buf_push(&mut self.buff, buf[..bytes].to_vec());
+                self.bytes += bytes as u64;
 
-                if let Some(tx) = &self.tx {
-                    let _ = tx.send(buf[..bytes].to_vec());
+                // Attached clients get the raw bytes
+                if let Some(tx) = &mut self.tx {
+                    tx.send(&buf[..bytes], policy);
                 }
 
+                // The journal keeps its own timestamps, so it gets the raw lines too
+                if let Some(journal) = journal {
+                    let _ = journal.append("stdout", self.journal_lines.lines(&buf[..bytes]));
+                }
+
+                let captured = match &mut self.prefixer {
+                    Some(prefixer) => prefixer.apply(&buf[..bytes]),
+                    None => buf[..bytes].to_vec(),
+                };
+
                 if let Some(stdout) = &mut self.def_stdout {
-                    let _ = stdout.write(&buf[..bytes]);
+                    let _ = stdout.write(&captured);
                 }
 
+                // Always push into the ring buffer
+                ring_buf_push(&mut self.buff, captured);
+
                 Ok(true)
             }
             Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
@@ -50,28 +466,63 @@ impl Stdout {
 
 struct Stderr {
     pipe: ChildStderr,
-    def_stderr: Option<File>,
-    tx: Option<SyncSender<Vec<u8>>>,
+    def_stderr: Option<OutputSink>,
+    tx: Option<Subscriber>,
     buff: VecDeque<Vec<u8>>,
+    prefixer: Option<LinePrefixer>,
+    journal_lines: LineBuffer,
+    bytes: u64, // Read from the pipe since the job started
 }
 
 impl Stderr {
+    // Nothing more comes from the pipe, the last line is not held anymore
+    fn finish(&mut self, journal: &mut Option<Journal>) {
+        let line = self.journal_lines.rest();
+        if let (Some(journal), Some(line)) = (journal, line) {
+            let _ = journal.append("stderr", vec![line]);
+        }
+        if let Some(stderr) = &mut self.def_stderr {
+            let _ = stderr.finish();
+        }
+    }
+
     // Return Ok(false) if we do not want to continue reading
-    fn forward(&mut self, buf: &mut [u8]) -> Result<bool, io::Error> {
+    fn forward(
+        &mut self,
+        buf: &mut [u8],
+        policy: &BackpressurePolicy,
+        journal: &mut Option<Journal>,
+    ) -> Result<bool, io::Error> {
         match self.pipe.read(buf) {
-            Ok(0) => Ok(false),
+            Ok(0) => {
+                self.finish(journal);
+                Ok(false)
+            }
             Ok(bytes) => {
-                // Always push into the ring buffer
-                ring_buf_push(&mut self.buff, buf[..bytes].to_vec());
+                self.bytes += bytes as u64;
+
+                // Attached clients get the raw bytes
+                if let Some(tx) = &mut self.tx {
+                    tx.send(&buf[..bytes], policy);
+                }
 
-                if let Some(tx) = &self.tx {
-                    let _ = tx.send(buf[..bytes].to_vec());
+                // The journal keeps its own timestamps, so it gets the raw lines too
+                if let Some(journal) = journal {
+                    let _ = journal.append("stderr", self.journal_lines.lines(&buf[..bytes]));
                 }
 
+                let captured = match &mut self.prefixer {
+                    Some(prefixer) => prefixer.apply(&buf[..bytes]),
+                    None => buf[..bytes].to_vec(),
+                };
+
                 if let Some(stderr) = &mut self.def_stderr {
-                    let _ = stderr.write(&buf[..bytes]);
+                    let _ = stderr.write(&captured);
                 }
 
+                // Always push into the ring buffer
+                ring_buf_push(&mut self.buff, captured);
+
                 Ok(true)
             }
             Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
@@ -83,6 +534,7 @@ impl Stderr {
 struct Tee {
     stdout: Stdout,
     stderr: Stderr,
+    journal: Option<Journal>,
 }
 
 impl Tee {
@@ -90,49 +542,89 @@ impl Tee {
     // need to call to a give buffer and the default value. TODO: Check if the
     // default value is the only way we need to use this tee moudle.
     fn new(
+        alias: &str,
         stdout: ChildStdout,
         stderr: ChildStderr,
         def_stdout: &str,
         def_stderr: &str,
+        options: &OutputOptions,
+        logger: &Logger,
     ) -> Result<Tee, io::Error> {
         Ok(Tee {
             stdout: Stdout {
                 pipe: stdout,
-                def_stdout: match def_stdout {
-                    "null" => None,
-                    o => Some(OpenOptions::new().create(true).write(true).open(o)?),
-                },
+                def_stdout: open_sink(def_stdout, alias, options, logger),
                 tx: None,
                 buff: VecDeque::with_capacity(IO_ROUTER_READ_BUF_LEN * DEQUE_BUF_LEN),
+                prefixer: LinePrefixer::new(alias, "stdout", options),
+                journal_lines: LineBuffer::new(),
+                bytes: 0,
             },
             stderr: Stderr {
                 pipe: stderr,
-                def_stderr: match def_stderr {
-                    "null" => None,
-                    o => Some(OpenOptions::new().create(true).write(true).open(o)?),
-                },
+                def_stderr: open_sink(def_stderr, alias, options, logger),
                 tx: None,
                 buff: VecDeque::with_capacity(IO_ROUTER_READ_BUF_LEN * DEQUE_BUF_LEN),
+                prefixer: LinePrefixer::new(alias, "stderr", options),
+                journal_lines: LineBuffer::new(),
+                bytes: 0,
             },
+            journal: None,
         })
     }
+
+    fn reopen(&mut self) -> Result<(), io::Error> {
+        if let Some(stdout) = &mut self.stdout.def_stdout {
+            stdout.reopen()?;
+        }
+        if let Some(stderr) = &mut self.stderr.def_stderr {
+            stderr.reopen()?;
+        }
+        Ok(())
+    }
+
+    fn flush_subscribers(&mut self) {
+        if let Some(tx) = &mut self.stdout.tx {
+            tx.flush();
+        }
+        if let Some(tx) = &mut self.stderr.tx {
+            tx.flush();
+        }
+    }
 }
 
 pub enum IoRouterRequest {
-    Create(String, ChildStdout, ChildStderr, String, String), // Alias, Stdout Pipe, Stderr Pipe, Default Stdout File, Default Stderr File
-    Remove(String),                                           // Alias
-    ReadBuff(String, Sender<(Vec<u8>, Vec<u8>)>), // Alias, Stdout Channel, Stderr Channel
+    Create(
+        String,
+        ChildStdout,
+        ChildStderr,
+        String,
+        String,
+        OutputOptions,
+        u64,
+    ), // Alias, Stdout Pipe, Stderr Pipe, Default Stdout File, Default Stderr File, Output Options, Run
+    Remove(String),                                        // Alias
+    Reopen(String, Sender<Result<(), OrchestratorError>>), // Alias (empty for all), Result Channel
+    ReadBuff(String, Sender<(Vec<u8>, Vec<u8>)>),          // Alias, Stdout Channel, Stderr Channel
     StartForwarding(
         String,
         SyncSender<Vec<u8>>,
         SyncSender<Vec<u8>>,
         Sender<Result<(), OrchestratorError>>,
     ), // Alias, Stdout Channel, Stderr Channel, Result Channel
-    StopForwarding(String),                       // Alias
+    StopForwarding(String),                                // Alias
 }
 
-pub fn route(requests: Receiver<IoRouterRequest>, logger: Logger) {
+pub fn route(
+    requests: Receiver<IoRouterRequest>,
+    logger: Logger,
+    policy: BackpressurePolicy,
+    journal: Option<JournalConfig>,
+    counters: IoCounters,
+) {
     let mut ios: HashMap<String, Tee> = HashMap::new();
+    // Bytes of previous runs, so the counters only grow while the server lives
+    let mut forwarded: HashMap<String, (u64, u64)> = HashMap::new();
     let period = Duration::from_millis(100);
     let mut buff = [0; IO_ROUTER_READ_BUF_LEN];
 
@@ -149,8 +641,8 @@ pub fn route(requests: Receiver<IoRouterRequest>, logger: Logger) {
                         if matches!(tee.stdout.tx, Some(_)) {
                             Err(OrchestratorError::JobAlreadyAttached)
                         } else {
-                            tee.stdout.tx = Some(stdout_channel);
-                            tee.stderr.tx = Some(stderr_channel);
+                            tee.stdout.tx = Some(Subscriber::new(stdout_channel));
+                            tee.stderr.tx = Some(Subscriber::new(stderr_channel));
                             Ok(())
                         }
                     } else {
@@ -185,49 +677,180 @@ pub fn route(requests: Receiver<IoRouterRequest>, logger: Logger) {
                         if matches!(tee.stdout.tx, Some(_)) {
                             // First drain all the pipes up to times
                             let mut times = DRAIN_TIMES;
-                            while tee.stdout.forward(&mut buff).unwrap_or(false) && times != 0 {
+                            while tee
+                                .stdout
+                                .forward(&mut buff, &policy, &mut tee.journal)
+                                .unwrap_or(false)
+                                && times != 0
+                            {
                                 times -= 1;
                             }
 
                             let mut times = DRAIN_TIMES;
-                            while tee.stderr.forward(&mut buff).unwrap_or(false) && times != 0 {
+                            while tee
+                                .stderr
+                                .forward(&mut buff, &policy, &mut tee.journal)
+                                .unwrap_or(false)
+                                && times != 0
+                            {
                                 times -= 1;
                             }
 
+                            // Give the coalesced bytes a last chance to be delivered
+                            tee.flush_subscribers();
+
                             // Then remove the forward channel
                             tee.stdout.tx = None;
                             tee.stderr.tx = None;
                         }
                     }
                 }
-                IoRouterRequest::Create(alias, stdout, stderr, def_stdout, def_stderr) => {
-                    match Tee::new(stdout, stderr, &def_stdout, &def_stderr) {
-                        Ok(tee) => {
+                IoRouterRequest::Create(
+                    alias,
+                    stdout,
+                    stderr,
+                    def_stdout,
+                    def_stderr,
+                    options,
+                    run,
+                ) => {
+                    match Tee::new(
+                        &alias,
+                        stdout,
+                        stderr,
+                        &def_stdout,
+                        &def_stderr,
+                        &options,
+                        &logger,
+                    ) {
+                        Ok(mut tee) => {
+                            // Losing the journal is not a reason to lose the output
+                            if let Some(config) = &journal {
+                                tee.journal = Journal::open(config, &alias, run)
+                                    .inspect_err(|err| {
+                                        logger::warn!(logger, "[{alias}] Opening journal: {err}")
+                                    })
+                                    .ok();
+                            }
                             ios.entry(alias).or_insert(tee);
                         }
                         Err(err) => logger::warn!(logger, "Creating new Tee: {}", err),
                     }
                 }
                 IoRouterRequest::Remove(alias) => {
-                    ios.remove(&alias);
+                    if let Some(mut tee) = ios.remove(&alias) {
+                        // Read what the job left in the pipes, last line included
+                        let mut times = DRAIN_TIMES;
+                        while tee
+                            .stdout
+                            .forward(&mut buff, &policy, &mut tee.journal)
+                            .unwrap_or(false)
+                            && times != 0
+                        {
+                            times -= 1;
+                        }
+
+                        let mut times = DRAIN_TIMES;
+                        while tee
+                            .stderr
+                            .forward(&mut buff, &policy, &mut tee.journal)
+                            .unwrap_or(false)
+                            && times != 0
+                        {
+                            times -= 1;
+                        }
+
+                        tee.flush_subscribers();
+                        tee.stdout.finish(&mut tee.journal);
+                        tee.stderr.finish(&mut tee.journal);
+                        let bytes = forwarded.entry(alias).or_default();
+                        bytes.0 += tee.stdout.bytes;
+                        bytes.1 += tee.stderr.bytes;
+                    }
+                }
+                IoRouterRequest::Reopen(alias, resp_channel) => {
+                    let result = if alias.is_empty() {
+                        ios.values_mut()
+                            .try_for_each(|tee| tee.reopen())
+                            .map_err(OrchestratorError::JobIoError)
+                    } else if let Some(tee) = ios.get_mut(&alias) {
+                        tee.reopen().map_err(OrchestratorError::JobIoError)
+                    } else {
+                        Err(OrchestratorError::JobNotFound)
+                    };
+
+                    if let Err(err) = resp_channel.send(result) {
+                        logger::error!(logger, "Sending to channel {err}");
+                    }
                 }
             }
         }
 
         for (_, tee) in &mut ios {
+            // Retry what could not be delivered to slow clients in previous rounds
+            tee.flush_subscribers();
+
             tee.stdout
-                .forward(&mut buff)
+                .forward(&mut buff, &policy, &mut tee.journal)
                 .inspect_err(|err| logger::error!(logger, "Reading from stdout: {err}"))
                 .ok();
             tee.stderr
-                .forward(&mut buff)
+                .forward(&mut buff, &policy, &mut tee.journal)
                 .inspect_err(|err| logger::error!(logger, "Reading from stderr: {err}"))
                 .ok();
         }
+
+        // Publish the counters of this round, previous runs included
+        if let Ok(mut counters) = counters.lock() {
+            for (alias, bytes) in &forwarded {
+                counters.insert(alias.clone(), *bytes);
+            }
+            for (alias, tee) in &ios {
+                let previous = forwarded.get(alias).copied().unwrap_or_default();
+                counters.insert(
+                    alias.clone(),
+                    (previous.0 + tee.stdout.bytes, previous.1 + tee.stderr.bytes),
+                );
+            }
+        }
         thread::sleep(period);
     }
 }
 
+fn open_output(path: &PathBuf, mode: &OutputMode) -> Result<File, io::Error> {
+    match mode {
+        OutputMode::Append => OpenOptions::new().create(true).append(true).open(path),
+        OutputMode::Truncate => OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path),
+    }
+}
+
+// Shift <path>.1 .. <path>.N one position and move path to <path>.1, the
+// oldest backup is overwritten. Without backups the file is just truncated
+// when reopened
+fn rotate_files(path: &PathBuf, backups: u16) -> Result<(), io::Error> {
+    let backup = |n: u16| {
+        let mut name = path.clone().into_os_string();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    };
+
+    if backups == 0 {
+        return Ok(());
+    }
+
+    for n in (1..backups).rev() {
+        if backup(n).exists() {
+            fs::rename(backup(n), backup(n + 1))?;
+        }
+    }
+
+    fs::rename(path, backup(1))
+}
+
 fn ring_buf_push(buff: &mut VecDeque<Vec<u8>>, element: Vec<u8>) {
     if buff.len() == buff.capacity() {
         buff.pop_front();
@@ -238,15 +861,9 @@ fn ring_buf_push(buff: &mut VecDeque<Vec<u8>>, element: Vec<u8>) {
 
 pub trait RouterRequest {
     fn read_buff(&self, alias: &str) -> (String, String);
-    fn create(
-        &self,
-        alias: &str,
-        stdout: ChildStdout,
-        stderr: ChildStderr,
-        def_stdout: &str,
-        def_stderr: &str,
-    );
+    fn create(&self, service: &Service, stdout: ChildStdout, stderr: ChildStderr, run: u64);
     fn remove(&self, alias: &str);
+    fn reopen(&self, alias: &str) -> Result<(), OrchestratorError>;
     fn start_forwarding(
         &self,
         alias: &str,
@@ -276,20 +893,16 @@ impl RouterRequest for Sender<IoRouterRequest> {
         }
     }
 
-    fn create(
-        &self,
-        alias: &str,
-        stdout: ChildStdout,
-        stderr: ChildStderr,
-        def_stdout: &str,
-        def_stderr: &str,
-    ) {
+    // The output goes where the service says
+    fn create(&self, service: &Service, stdout: ChildStdout, stderr: ChildStderr, run: u64) {
         let _ = self.send(IoRouterRequest::Create(
-            alias.to_string(),
+            service.alias.clone(),
             stdout,
             stderr,
-            def_stdout.to_string(),
-            def_stderr.to_string(),
+            service.stdout.clone(),
+            service.stderr.clone(),
+            service.output.clone(),
+            run,
         ));
     }
 
@@ -297,6 +910,17 @@ impl RouterRequest for Sender<IoRouterRequest> {
         let _ = self.send(IoRouterRequest::Remove(alias.to_string()));
     }
 
+    fn reopen(&self, alias: &str) -> Result<(), OrchestratorError> {
+        let (resp_tx, resp_rx) = mpsc::channel();
+
+        self.send(IoRouterRequest::Reopen(alias.to_string(), resp_tx))
+            .map_err(|_| OrchestratorError::InternalChannelSendError)?;
+
+        resp_rx
+            .recv()
+            .unwrap_or(Err(OrchestratorError::InternalChannelReceiveError))
+    }
+
     fn start_forwarding(
         &self,
         alias: &str,
@@ -4,10 +4,11 @@
 
 use libc;
 use logger::{self, LogLevel};
+use serde::{Deserialize, Serialize};
 use std::{
     fmt::Display,
     io::{self, Write},
-    process::ChildStdin,
+    process::{Child, ChildStdin},
     sync::mpsc::{self, Sender},
     thread,
     time::Duration,
@@ -15,9 +16,14 @@ use std::{
 use taskmeister::{self, ResponsePart};
 
 use crate::{
+    history::RunTrigger,
+    hooks::{self, HookContext, HookPoint},
     io_router::{self, RouterRequest},
+    journal::{self, SearchQuery},
     orchestrate::{Orchestrator, OrchestratorError},
-    watcher::{Watched, WatchedTimeout},
+    resources::{self, ProcUsage, Sampler, TopOptions},
+    service::{self, StopAction, StopStep},
+    watcher::{Watched, WatchedLimits, WatchedTimeout},
 };
 
 // Flags that are consumed upon use
@@ -25,6 +31,7 @@ use crate::{
 pub struct JobFlags {
     pub remove_service: bool, // Flag to remove service once the job finish
     pub restart_job: bool,    // Flag to restart job, only used for reload config
+    pub watchdog: bool,       // The restart comes from a watchdog limit
 }
 
 impl JobFlags {
@@ -32,6 +39,7 @@ impl JobFlags {
         JobFlags {
             remove_service: false,
             restart_job: false,
+            watchdog: false,
         }
     }
 
@@ -42,12 +50,53 @@ impl JobFlags {
     }
 }
 
+/// The start or stop of a job waiting for its pre_start or pre_stop hook
+pub enum PendingHook {
+    Start(RunTrigger, Option<Sender<ResponsePart>>), // Client told once it started
+    Stop(bool),                                      // Paused when asked to stop
+}
+
 pub struct Job {
     pub status: JobStatus,
     pub started: Option<String>,
     pub retries: u8,
+    pub stop_step: usize, // Step of the stop sequence running while stopping
+    pub restarts: u64,    // Every start but the manual ones, never reset
+    pub last_exit: Option<ExitReason>,
     pub flags: JobFlags,
     pub stdin: Option<ChildStdin>,
+    pub hook: Option<PendingHook>,
+    pub hook_run: u64, // Of the last waited hook, older results are ignored
+}
+
+/// Why a job finished: a normal exit or a signal it did not handle
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub enum ExitReason {
+    Code(i32),
+    Signal { signal: i32, core_dumped: bool },
+}
+
+impl Display for ExitReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitReason::Code(exit_code) => write!(f, "Exit Code: {}", exit_code),
+            ExitReason::Signal {
+                signal,
+                core_dumped,
+            } => {
+                write!(
+                    f,
+                    "Signal: SIG{} ({})",
+                    service::signal_to_str(*signal).unwrap_or("UNKNOWN"),
+                    signal
+                )?;
+                if *core_dumped {
+                    write!(f, ", Core Dumped")?;
+                }
+                Ok(())
+            }
+        }
+    }
 }
 
 #[derive(PartialEq, Clone)]
@@ -56,8 +105,25 @@ pub enum JobStatus {
     Starting,
     Running(bool), // While false job is not healthy
     Stopping,
-    Finished(i32),
+    Finished(ExitReason),
     TimedOut,
+    Paused, // Stopped with SIGSTOP, no timeouts run meanwhile
+}
+
+impl JobStatus {
+    // Short name without the details, used by metrics and events
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobStatus::Created => "created",
+            JobStatus::Starting => "starting",
+            JobStatus::Running(false) => "running",
+            JobStatus::Running(true) => "healthy",
+            JobStatus::Stopping => "stopping",
+            JobStatus::Finished(_) => "finished",
+            JobStatus::TimedOut => "timed_out",
+            JobStatus::Paused => "paused",
+        }
+    }
 }
 
 impl Display for JobStatus {
@@ -68,8 +134,9 @@ impl Display for JobStatus {
             JobStatus::Running(false) => write!(f, "Running"),
             JobStatus::Running(true) => write!(f, "Running (Healthy)"),
             JobStatus::Stopping => write!(f, "Stopping"),
-            JobStatus::Finished(exit_code) => write!(f, "Finished (Exit Code: {})", exit_code),
+            JobStatus::Finished(reason) => write!(f, "Finished ({})", reason),
             JobStatus::TimedOut => write!(f, "Watcher Tick"),
+            JobStatus::Paused => write!(f, "Paused"),
         }
     }
 }
@@ -82,9 +149,14 @@ impl Orchestrator {
         Ok(self.jobs.entry(alias.to_string()).or_insert(Job {
             status: JobStatus::Created,
             retries: 0,
+            stop_step: 0,
+            restarts: 0,
+            last_exit: None,
             flags: JobFlags::default(),
             started: None,
             stdin: None,
+            hook: None,
+            hook_run: 0,
         }))
     }
 
@@ -122,9 +194,10 @@ impl Orchestrator {
                 .ok_or(OrchestratorError::JobHasNoIoHandle)?,
         );
 
-        // Create an I/O handler
+        // Create an I/O handler, its journal records the run the history does
+        let run = self.history.next_run(alias);
         self.io_router_requests
-            .create(alias, stdout, stderr, &service.stdout, &service.stderr);
+            .create(&service, stdout, stderr, run);
 
         // Add handler to the watched jobs
         let mut watched = self.watched.lock().unwrap();
@@ -133,37 +206,91 @@ impl Orchestrator {
             vec![Watched {
                 process: child,
                 previous_status: JobStatus::Starting,
+                exited: None,
+                sampler: Sampler::new(),
+                limits: WatchedLimits::new(service.watchdog.clone()),
                 timeout: WatchedTimeout::new(Some(Duration::from_secs(service.start_time))),
             }],
         ))
     }
 
-    // Stops the job according to the signal specified in the service configuration
-    fn stop_job(&self, alias: &str) -> Result<(), OrchestratorError> {
+    // Runs a step of the stop sequence of the service, past the last one the
+    // job is killed
+    pub fn stop_job(&self, alias: &str, step: usize) -> Result<(), OrchestratorError> {
         let service = self
             .get_services()
             .get(alias)
             .cloned()
             .ok_or(OrchestratorError::ServiceNotFound)?;
 
-        self.kill_job(
-            alias,
-            service.stop_signal,
-            Duration::from_secs(service.stop_wait),
-        )
+        match service.stop_steps().get(step) {
+            Some(StopStep {
+                action: StopAction::Signal { signal },
+                wait,
+            }) => self.kill_job(alias, *signal, Some(Duration::from_secs(*wait))),
+            Some(StopStep {
+                action: StopAction::Cmd { cmd },
+                wait,
+            }) => self.stop_command(&service, alias, cmd, Duration::from_secs(*wait)),
+            None => self.kill_job(
+                alias,
+                libc::SIGKILL,
+                Some(Duration::from_secs(service.stop_wait)),
+            ),
+        }
+    }
+
+    // Runs a command that should make the job stop, setting the timeout for
+    // the process to stop
+    fn stop_command(
+        &self,
+        service: &service::Service,
+        alias: &str,
+        cmd: &str,
+        timeout: Duration,
+    ) -> Result<(), OrchestratorError> {
+        logger::info!(self.logger, "[{}] Stopping with: {}", alias, cmd);
+
+        let job_pids = self.reset_stop_timeout(alias, timeout)?;
+        let mut child = spawn_helper(service, alias, cmd, job_pids.first())?;
+
+        // Nothing waits for the result, the timeout decides, just reap it
+        thread::spawn(move || child.wait());
+
+        Ok(())
     }
 
-    // Sends a specific stop signal to the job, setting the timeout for the process to stop
+    // Sends a specific signal to the job. With a timeout it is a stop signal,
+    // and the process has that time to stop
     pub fn kill_job(
         &self,
         alias: &str,
         signal: i32,
-        timeout: Duration,
+        timeout: Option<Duration>,
     ) -> Result<(), OrchestratorError> {
         logger::info!(self.logger, "[{}] Killing({})", alias, signal);
 
-        // Get all the jobs id and restart timeout
-        let job_pids: Vec<u32> = self
+        let job_pids = match timeout {
+            Some(timeout) => self.reset_stop_timeout(alias, timeout)?,
+            None => self.get_pid(alias)?,
+        };
+
+        // Kill the jobs
+        for pid in job_pids {
+            // TODO: Avoid early return?
+            kill(pid, signal).map_err(|err| OrchestratorError::JobIoError(err))?;
+        }
+
+        Ok(())
+    }
+
+    // Get all the jobs id and restart timeout
+    fn reset_stop_timeout(
+        &self,
+        alias: &str,
+        timeout: Duration,
+    ) -> Result<Vec<u32>, OrchestratorError> {
+        Ok(self
             .watched
             .lock()
             .unwrap()
@@ -174,58 +301,82 @@ impl Orchestrator {
                 watched_job.timeout = WatchedTimeout::new(Some(timeout));
                 watched_job.process.id()
             })
-            .collect();
-
-        // Kill the jobs
-        for pid in job_pids {
-            // TODO: Avoid early return?
-            kill(pid, signal).map_err(|err| OrchestratorError::JobIoError(err))?;
-        }
-
-        Ok(())
+            .collect())
     }
 
     // #################### REQUESTS ####################
-    pub fn start_request(&mut self, alias: &str) -> Result<(), OrchestratorError> {
+    /// Starts the job, or runs its pre_start hook first and starts it once
+    /// the hook is done. Then the outcome is only sent to the response
+    pub fn start_request(
+        &mut self,
+        alias: &str,
+        trigger: RunTrigger,
+        response: Option<Sender<ResponsePart>>,
+    ) -> Result<(), OrchestratorError> {
         // Get or create a new job
         let job = self.create_job(alias)?;
+        if matches!(job.hook, Some(PendingHook::Start(..))) {
+            return Err(OrchestratorError::ServiceAlreadyStarted);
+        }
 
         // Only finished, created and Free are considered valid states to start a job
-        let mut response = match job.status {
+        match job.status {
             JobStatus::Starting
             | JobStatus::Running(_)
             | JobStatus::Stopping
-            | JobStatus::TimedOut => Err(OrchestratorError::ServiceAlreadyStarted),
+            | JobStatus::TimedOut
+            | JobStatus::Paused => return Err(OrchestratorError::ServiceAlreadyStarted),
             JobStatus::Finished(_) => {
                 // At this point event loop will have moved the job
                 // out from the watcher
-                Ok(())
             }
-            JobStatus::Created => Ok(()),
+            JobStatus::Created => (),
         };
 
-        // If response is positive, start the job
-        if let Ok(_) = response {
-            response = match self.start_job(alias) {
-                Ok(res) => {
-                    self.set_job_status(alias, JobStatus::Starting);
-                    self.set_job_timestamp(alias);
-
-                    if let Some(_) = res {
-                        // TODO: Do something with old jobs in this case?
-                        logger::warn!(
-                            self.logger,
-                            "Started new jobs but old where not cleaned up from the watcher"
-                        );
-                    }
-
-                    Ok(())
+        // A failing pre_start hook aborts the start, see manage_hook_event
+        if self.wait_hook(
+            alias,
+            HookPoint::PreStart,
+            PendingHook::Start(trigger.clone(), response),
+        ) {
+            return Ok(());
+        }
+
+        self.launch_job(alias, trigger)
+    }
+
+    // Starts the process of the job, its pre_start hook already ran
+    pub fn launch_job(
+        &mut self,
+        alias: &str,
+        trigger: RunTrigger,
+    ) -> Result<(), OrchestratorError> {
+        match self.start_job(alias) {
+            Ok(res) => {
+                self.set_job_status(alias, JobStatus::Starting);
+                self.set_job_timestamp(alias);
+                if trigger != RunTrigger::Manual
+                    && let Some(job) = self.jobs.get_mut(alias)
+                {
+                    job.restarts += 1;
+                }
+                self.history
+                    .start(alias, trigger)
+                    .inspect_err(|err| logger::error!(self.logger, "Saving history: {err}"))
+                    .ok();
+
+                if let Some(_) = res {
+                    // TODO: Do something with old jobs in this case?
+                    logger::warn!(
+                        self.logger,
+                        "Started new jobs but old where not cleaned up from the watcher"
+                    );
                 }
-                Err(err) => Err(err),
-            };
-        };
 
-        response
+                Ok(())
+            }
+            Err(err) => Err(err),
+        }
     }
 
     pub fn stop_request(
@@ -240,9 +391,29 @@ impl Orchestrator {
             .get_mut(alias)
             .ok_or(OrchestratorError::JobNotFound)?;
 
+        // A start waiting for its pre_start hook is cancelled and its client
+        // told, the late hook result is then ignored. A restart lets it go on
+        if matches!(job.hook, Some(PendingHook::Start(..))) {
+            if restart_job {
+                return Ok(());
+            }
+            if let Some(PendingHook::Start(_, Some(response))) = job.hook.take() {
+                response
+                    .send(Err::<(), _>(OrchestratorError::StartCancelled).into())
+                    .ok();
+            }
+            if job.status == JobStatus::Created {
+                if remove_service {
+                    self.remove_service(alias);
+                }
+                return Ok(());
+            }
+        }
+
         // Only starting and Running are considered valid states to stop a job
+        let paused = job.status == JobStatus::Paused;
         let mut response = match job.status {
-            JobStatus::Starting | JobStatus::Running(_) => Ok(()),
+            JobStatus::Starting | JobStatus::Running(_) | JobStatus::Paused => Ok(()),
             JobStatus::Stopping | JobStatus::TimedOut => {
                 Err(OrchestratorError::ServiceAlreadyStopping)
             }
@@ -265,18 +436,167 @@ impl Orchestrator {
         // If response is positive, stop the job
         if let Ok(_) = response {
             // Update job
-            job.status = JobStatus::Stopping;
             job.flags = JobFlags {
                 remove_service,
                 restart_job,
+                watchdog: false,
             };
+            job.stop_step = 0;
+            self.set_job_status(alias, JobStatus::Stopping);
+
+            // The job is stopped even if the hook fails, see manage_hook_event
+            if self.wait_hook(alias, HookPoint::PreStop, PendingHook::Stop(paused)) {
+                return Ok(());
+            }
 
-            response = self.stop_job(&alias);
+            response = self.signal_stop(alias, paused);
         };
 
         response
     }
 
+    // Sends the first step of the stop sequence, its pre_stop hook already ran
+    pub fn signal_stop(&self, alias: &str, paused: bool) -> Result<(), OrchestratorError> {
+        self.stop_job(alias, 0)?;
+
+        // A paused job only gets the stop signal once it runs again
+        if paused {
+            self.kill_job(alias, libc::SIGCONT, None)?;
+        }
+
+        Ok(())
+    }
+
+    // Runs a hook of the job in its thread, the job keeps what to do once the
+    // hook event tells it is done. False when the service has no hook there
+    fn wait_hook(&mut self, alias: &str, point: HookPoint, pending: PendingHook) -> bool {
+        let Some(service) = self.get_services().get(alias).cloned() else {
+            return false;
+        };
+        if point.hook(&service).is_none() {
+            return false;
+        }
+        let context = self.hook_context(alias, None);
+        let Some(job) = self.jobs.get_mut(alias) else {
+            return false;
+        };
+
+        job.hook_run += 1;
+        job.hook = Some(pending);
+        hooks::spawn_waited(
+            &service,
+            point,
+            job.hook_run,
+            context,
+            self.messages_tx.clone(),
+            self.logger.clone(),
+        );
+
+        true
+    }
+
+    // Runs a hook of the job without waiting for it
+    pub fn spawn_hook(&self, alias: &str, point: HookPoint, context: HookContext) {
+        if let Some(service) = self.get_services().get(alias) {
+            hooks::spawn(service, point, context, self.logger.clone());
+        }
+    }
+
+    pub fn hook_context(&self, alias: &str, exit: Option<ExitReason>) -> HookContext {
+        HookContext {
+            retries: self.jobs.get(alias).map_or(0, |job| job.retries),
+            pid: self
+                .get_pid(alias)
+                .ok()
+                .and_then(|pids| pids.first().copied()),
+            exit,
+        }
+    }
+
+    // Sends a signal to every process of the job, its state is left as it is
+    pub fn signal_request(&self, alias: &str, signal: i32) -> Result<(), OrchestratorError> {
+        let job = self.jobs.get(alias).ok_or(OrchestratorError::JobNotFound)?;
+
+        match job.status {
+            JobStatus::Created | JobStatus::Finished(_) => Err(OrchestratorError::JobNotRunning),
+            _ => self.kill_job(alias, signal, None),
+        }
+    }
+
+    // Asks the job to reload in place with the signal or command of the service
+    pub fn reload_request(&self, alias: &str) -> Result<(), OrchestratorError> {
+        let job = self.jobs.get(alias).ok_or(OrchestratorError::JobNotFound)?;
+        let service = self
+            .get_services()
+            .get(alias)
+            .cloned()
+            .ok_or(OrchestratorError::ServiceNotFound)?;
+
+        if !matches!(job.status, JobStatus::Starting | JobStatus::Running(_)) {
+            return Err(OrchestratorError::JobNotRunning);
+        }
+
+        if let Some(signal) = service.reload_signal {
+            return self.kill_job(alias, signal, None);
+        }
+
+        let Some(cmd) = &service.reload_cmd else {
+            return Err(OrchestratorError::ReloadNotConfigured);
+        };
+
+        logger::info!(self.logger, "[{}] Reloading with: {}", alias, cmd);
+
+        let pids = self.get_pid(alias)?;
+        let mut child = spawn_helper(&service, alias, cmd, pids.first())?;
+        let logger = self.logger.clone();
+        let alias = alias.to_string();
+
+        thread::spawn(move || match child.wait() {
+            Ok(status) if status.success() => (),
+            Ok(status) => logger::warn!(logger, "[{alias}] Reload command exited with {status}"),
+            Err(err) => logger::warn!(logger, "[{alias}] Reload command: {err}"),
+        });
+
+        Ok(())
+    }
+
+    pub fn pause_request(&mut self, alias: &str) -> Result<(), OrchestratorError> {
+        let job = self.jobs.get(alias).ok_or(OrchestratorError::JobNotFound)?;
+
+        if !matches!(job.status, JobStatus::Starting | JobStatus::Running(_)) {
+            return Err(OrchestratorError::JobNotRunning);
+        }
+
+        // A stopped process can not prove it is healthy, so no timeout runs
+        self.kill_job(alias, libc::SIGSTOP, None)?;
+        self.remove_watched_timeout(alias);
+        self.set_job_status(alias, JobStatus::Paused);
+
+        Ok(())
+    }
+
+    // The job gets its start time again to become healthy
+    pub fn resume_request(&mut self, alias: &str) -> Result<(), OrchestratorError> {
+        let job = self.jobs.get(alias).ok_or(OrchestratorError::JobNotFound)?;
+        let service = self
+            .get_services()
+            .get(alias)
+            .ok_or(OrchestratorError::ServiceNotFound)?;
+
+        if job.status != JobStatus::Paused {
+            return Err(OrchestratorError::JobNotPaused);
+        }
+
+        self.kill_job(
+            alias,
+            libc::SIGCONT,
+            Some(Duration::from_secs(service.start_time)),
+        )?;
+        self.set_job_status(alias, JobStatus::Starting);
+
+        Ok(())
+    }
+
     pub fn job_status(&self, alias: &str) -> Result<String, OrchestratorError> {
         // Get the job
         let job = self.jobs.get(alias).ok_or(OrchestratorError::JobNotFound)?;
@@ -291,6 +611,8 @@ impl Orchestrator {
         Ok(format!(
             r#"status: {} Since {}
 PIDs: {}
+Usage:
+{}
 Configuration: {}
 Stdout:
 
@@ -307,18 +629,40 @@ Stderr:
                 .map(|pid| pid.to_string())
                 .collect::<Vec<_>>()
                 .join(", "),
+            format_usage(&self.get_usage(alias).unwrap_or_default()),
             service.file.display(),
             stdout,
             stderr,
         ))
     }
 
+    /// The service as the job runs it, after the defaults and templates
+    pub fn service_config(&self, alias: &str) -> Result<String, OrchestratorError> {
+        let service = self
+            .get_services()
+            .get(alias)
+            .ok_or(OrchestratorError::ServiceNotFound)?;
+
+        let dropins: Vec<String> = service
+            .dropins
+            .iter()
+            .map(|dropin| format!("# Drop-in: {}\n", dropin.display()))
+            .collect();
+
+        Ok(format!(
+            "# Defined: {}\n{}{}",
+            service.file.display(),
+            dropins.concat(),
+            toml::to_string(service).unwrap_or_else(|err| format!("# Not printable: {err}"))
+        ))
+    }
+
     pub fn list_services(&self) -> String {
         self.get_services()
             .sorted()
             .iter()
             .fold(String::new(), |acc, service| {
-                let (status, pid) = match self.jobs.get(&service.alias) {
+                let (status, pid, usage) = match self.jobs.get(&service.alias) {
                     Some(job) => (
                         job.status.to_string(),
                         self.get_pid(&service.alias)
@@ -327,14 +671,20 @@ Stderr:
                             .map(|pid| pid.to_string())
                             .collect::<Vec<_>>()
                             .join(", "),
+                        format_usage(&self.get_usage(&service.alias).unwrap_or_default()),
+                    ),
+                    None => (
+                        "Not Yet Started".to_owned(),
+                        "N/A".to_owned(),
+                        String::new(),
                     ),
-                    None => ("Not Yet Started".to_owned(), "N/A".to_owned()),
                 };
                 acc + &format!(
-                    "\n{}:\t[{} PID: {}]\n\tDefined: {}\n",
+                    "\n{}:\t[{} PID: {}]\n{}\tDefined: {}\n",
                     &service.alias,
                     status,
                     pid,
+                    usage,
                     service.file.display(),
                 )
             })
@@ -382,6 +732,81 @@ Stderr:
         self.io_router_requests.stop_forwarding(alias)
     }
 
+    // Reopen the output files of a job, or of every job if alias is empty
+    pub fn reopen_job_output(&self, alias: &str) -> Result<(), OrchestratorError> {
+        self.io_router_requests.reopen(alias)
+    }
+
+    // Searches the journal of a job in its own thread, since it may need to
+    // read a lot from disk
+    pub fn search_job_output(&self, alias: &str, query: SearchQuery, tx: Sender<ResponsePart>) {
+        let Some(config) = self.journal.clone() else {
+            let _ = tx.send(ResponsePart::Error("Journal is not enabled".to_string()));
+            return;
+        };
+        // The alias names a directory under the journal, only the known ones
+        if self.get_services().get(alias).is_none() {
+            let _ = tx.send(Err::<(), _>(OrchestratorError::ServiceNotFound).into());
+            return;
+        }
+        let alias = alias.to_string();
+
+        thread::spawn(move || {
+            let response = journal::search(&config, &alias, &query).map(|records| {
+                if records.is_empty() {
+                    return "No matches".to_string();
+                }
+                records
+                    .iter()
+                    .map(|record| record.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            });
+            let _ = tx.send(response.into());
+        });
+    }
+
+    // Streams a table with the resources of every running job until the
+    // client goes away or the refreshes asked for are done
+    pub fn top_jobs(&self, options: TopOptions, tx: Sender<ResponsePart>) {
+        let watched = self.watched.clone();
+        let interval =
+            Duration::from_secs(options.interval.unwrap_or(resources::TOP_INTERVAL).max(1));
+
+        thread::spawn(move || {
+            let mut refreshes = 0;
+
+            while options.count.is_none_or(|count| refreshes < count) {
+                let rows = watched
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .flat_map(|(alias, jobs)| {
+                        jobs.iter().filter_map(|job| {
+                            job.sampler
+                                .usage
+                                .clone()
+                                .map(|usage| (alias.clone(), job.process.id(), usage))
+                        })
+                    })
+                    .collect();
+
+                // Clear the screen so every refresh replaces the last one
+                let table = format!("\x1b[2J\x1b[H{}", resources::top_table(rows, &options.sort));
+                if tx.send(ResponsePart::Stream(table.into_bytes())).is_err() {
+                    return;
+                }
+
+                refreshes += 1;
+                if options.count.is_none_or(|count| refreshes < count) {
+                    thread::sleep(interval);
+                }
+            }
+
+            let _ = tx.send(ResponsePart::Info("OK [End Of Stream]".to_string()));
+        });
+    }
+
     pub fn forward_stdin_job(
         &mut self,
         alias: &str,
@@ -405,6 +830,35 @@ Stderr:
 
 // #################### UTILS ####################
 
+// One line per process of a job with the resources sampled last
+fn format_usage(usage: &[(u32, Option<ProcUsage>)]) -> String {
+    usage.iter().fold(String::new(), |acc, (pid, usage)| {
+        acc + &match usage {
+            Some(usage) => format!("\t{pid}: {usage}\n"),
+            None => format!("\t{pid}: Not Sampled\n"),
+        }
+    })
+}
+
+// Runs a command of the service for the job, telling it the job alias and pid
+fn spawn_helper(
+    service: &service::Service,
+    alias: &str,
+    cmd: &str,
+    pid: Option<&u32>,
+) -> Result<Child, OrchestratorError> {
+    service
+        .helper_command(cmd)
+        .map_err(OrchestratorError::JobIoError)?
+        .env("TASKMEISTER_ALIAS", alias)
+        .env(
+            "TASKMEISTER_PID",
+            pid.map_or(String::new(), |pid| pid.to_string()),
+        )
+        .spawn()
+        .map_err(OrchestratorError::JobIoError)
+}
+
 fn kill(pid: u32, signal: i32) -> io::Result<()> {
     if unsafe { libc::kill(pid as i32, signal) } == -1 {
         Err(io::Error::last_os_error())
@@ -0,0 +1,240 @@
+use serde::{Deserialize, Deserializer, Serialize, de};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use taskmeister::dir_utils;
+
+// Maximum number of matches returned by a search unless asked otherwise
+pub const SEARCH_LIMIT: usize = 500;
+// Stream name of the records written by the journal itself
+const JOURNAL_STREAM: &str = "journal";
+
+/// On disk history of the output of every job. Each job has its own directory
+/// with numbered segments, once the segments exceed the limit the oldest is
+/// removed.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct JournalConfig {
+    pub dir: PathBuf,
+    pub segment_size: u64, // In bytes
+    #[serde(deserialize_with = "deserialize_segments")]
+    pub segments: u64, // Segments kept per job, the one being written included
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Record {
+    pub time: u64, // Milliseconds since epoch
+    pub run: u64,  // Same number as in the history
+    pub stream: String,
+    pub line: String,
+}
+
+impl std::fmt::Display for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} run {} [{}] {}",
+            logger::rfc3339(SystemTime::UNIX_EPOCH + Duration::from_millis(self.time)),
+            self.run,
+            self.stream,
+            self.line
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SearchQuery {
+    pub pattern: String,
+    pub since: Option<SystemTime>,
+    pub until: Option<SystemTime>,
+    pub run: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+impl SearchQuery {
+    fn matches(&self, record: &Record) -> bool {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_millis(record.time);
+
+        self.since.is_none_or(|since| time >= since)
+            && self.until.is_none_or(|until| time <= until)
+            && self.run.is_none_or(|run| record.run == run)
+            && record.line.contains(&self.pattern)
+    }
+}
+
+pub struct Journal {
+    dir: PathBuf,
+    config: JournalConfig,
+    segment: File,
+    index: u64, // Number of the segment being written
+    size: u64,  // Size of the segment being written
+    run: u64,
+}
+
+impl Journal {
+    /// Opens the journal of a job for a new run, numbered by the history
+    pub fn open(config: &JournalConfig, alias: &str, run: u64) -> Result<Journal, io::Error> {
+        let dir = job_dir(config, alias);
+        fs::create_dir_all(&dir)?;
+
+        let index = list_segments(&dir)?.last().map_or(0, |(index, _)| *index);
+
+        let mut journal = Journal {
+            segment: open_segment(&dir, index)?,
+            dir,
+            config: config.clone(),
+            index,
+            size: 0,
+            run,
+        };
+        journal.size = journal.segment.metadata()?.len();
+
+        // Mark the start, so runs without output still take their number
+        journal.append(JOURNAL_STREAM, vec![format!("Run {run} started")])?;
+
+        Ok(journal)
+    }
+
+    pub fn append(&mut self, stream: &str, lines: Vec<String>) -> Result<(), io::Error> {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        for line in lines {
+            let mut record = serde_json::to_vec(&Record {
+                time,
+                run: self.run,
+                stream: stream.to_string(),
+                line,
+            })?;
+            record.push(b'\n');
+
+            if self.size > 0 && self.size + record.len() as u64 > self.config.segment_size {
+                self.next_segment()?;
+            }
+
+            self.segment.write_all(&record)?;
+            self.size += record.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn next_segment(&mut self) -> Result<(), io::Error> {
+        self.index += 1;
+        self.segment = open_segment(&self.dir, self.index)?;
+        self.size = 0;
+
+        // Drop the oldest segments over the limit
+        for (index, path) in list_segments(&self.dir)? {
+            if index + self.config.segments <= self.index {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the most recent records of a job matching the query, oldest first
+pub fn search(
+    config: &JournalConfig,
+    alias: &str,
+    query: &SearchQuery,
+) -> Result<Vec<Record>, io::Error> {
+    let dir = job_dir(config, alias);
+    if !dir.is_dir() {
+        return Err(io::Error::other(format!("No journal for {alias}")));
+    }
+
+    let limit = query.limit.unwrap_or(SEARCH_LIMIT);
+    let mut found = Vec::new();
+
+    for (_, path) in list_segments(&dir)? {
+        for line in BufReader::new(File::open(path)?).lines() {
+            // A line cut by a crash while writing is just skipped
+            let Ok(record) = serde_json::from_str::<Record>(&line?) else {
+                continue;
+            };
+
+            if query.matches(&record) {
+                found.push(record);
+            }
+        }
+
+        if found.len() > limit {
+            found.drain(..found.len() - limit);
+        }
+    }
+
+    Ok(found)
+}
+
+/// The last run recorded for a job, so the numbers survive server restarts
+pub fn last_run(config: &JournalConfig, alias: &str) -> Result<Option<u64>, io::Error> {
+    let dir = job_dir(config, alias);
+    if !dir.is_dir() {
+        return Ok(None);
+    }
+
+    // The newest segment may still be empty
+    for (_, path) in list_segments(&dir)?.iter().rev() {
+        if let Some(run) = segment_last_run(path)? {
+            return Ok(Some(run));
+        }
+    }
+    Ok(None)
+}
+
+fn job_dir(config: &JournalConfig, alias: &str) -> PathBuf {
+    dir_utils::expand_home_dir(&config.dir).join(alias)
+}
+
+fn open_segment(dir: &Path, index: u64) -> Result<File, io::Error> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(format!("{index:010}.jsonl")))
+}
+
+// Segments of a job directory sorted by number
+fn list_segments(dir: &Path) -> Result<Vec<(u64, PathBuf)>, io::Error> {
+    let mut segments = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let index = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".jsonl"))
+            .and_then(|index| index.parse::<u64>().ok());
+
+        if let Some(index) = index {
+            segments.push((index, path));
+        }
+    }
+
+    segments.sort();
+    Ok(segments)
+}
+
+fn segment_last_run(segment: &Path) -> Result<Option<u64>, io::Error> {
+    Ok(BufReader::new(File::open(segment)?)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str::<Record>(&line).ok())
+        .last()
+        .map(|record| record.run))
+}
+
+// With none kept, the segment just opened would be removed
+fn deserialize_segments<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match u64::deserialize(deserializer)? {
+        0 => Err(de::Error::custom("segments must be at least 1")),
+        segments => Ok(segments),
+    }
+}
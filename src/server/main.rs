@@ -1,14 +1,26 @@
+mod check;
 mod config;
+mod config_watch;
 mod events;
+mod history;
+mod hooks;
 mod io_router;
 mod jobs;
+mod journal;
+mod metrics;
 mod orchestrate;
+mod reload;
+mod resources;
+mod rollout;
 mod service;
 mod watcher;
 
 use config::Config;
+use journal::SearchQuery;
 use logger::{LogLevel, Logger};
-use orchestrate::{Orchestrator, OrchestratorMsg, OrchestratorRequest};
+use orchestrate::{Orchestrator, OrchestratorMsg, OrchestratorRequest, OrchestratorSender};
+use reload::ServerReload;
+use resources::TopOptions;
 use serde_json::Deserializer;
 use service::{ServiceAction, Services};
 use std::{
@@ -16,35 +28,57 @@ use std::{
     io::{self, Write},
     net::{TcpListener, TcpStream},
     sync::{
-        atomic::{AtomicBool, Ordering},
-        mpsc::{self, Sender},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc,
     },
     thread::{self},
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 use taskmeister::{Request, ResponsePart, dir_utils};
 
 pub const CLI_HELP: &str = r#"Commands:
 	start [st]	Start a service
 	stop [sp]	Stop a job
-	restart [rs]	Restart a job
-	status [stat]	Show the current status of a job
+	restart [rs]	Restart a job, with --rolling the jobs of a service one at a
+			time or --batch=N at a time, each waiting for the previous
+			ones to be healthy. Stops at the first one that is not
+	status [stat]	Show the current status of a job, with --config the
+			effective configuration after defaults and templates
 	attach [at]	Attach the job to the current client
 	detach [dt] 	Detach the job from every client
-	reload [rl]	Reload the configuration for the services
+	signal [sig]	Send a signal to a job: signal <alias> <SIG>
+	pause	Stop a job with SIGSTOP until resumed
+	resume	Continue a paused job with SIGCONT
+	reopen [ro]	Reopen the output files of a job, or of all jobs
+	reload [rl]	Reload the configuration for the services, or with an alias
+			reload that job in place with its reload_signal or reload_cmd.
+			With --dry-run only show what would change for each alias
+	scale		Change the number of jobs of a service: scale <alias> <n>, the
+			new jobs start if the service runs and the last ones stop.
+			Kept until the file changes numprocs, or with --persist saved in it
+	check-config [check]	Check the service files, every problem with its file, line and column
+	history [hs]	Show the last runs of a job with their exit and resource usage
+	search [grep]	Search the output history of a job:
+			search <alias> <text> [--since=DATE] [--until=DATE] [--run=N] [--limit=N]
+			DATE is RFC 3339 (2025-01-31T13:04:05Z) or a day (2025-01-31)
+	events [subscribe]	Stream the state changes of every job, or of one service, as JSON
 	list [ls]	List all loaded services
+	top		Show a refreshing table with the resources used by every job:
+			top [--sort=cpu|rss|fds|threads|uptime|alias] [--interval=SECS] [--count=N]
 	quit [q]	Exit client
 	stop_server	Stop the server
 	help [?]	Show this help
 "#;
 
-static SIGHUP_FLAG: AtomicBool = AtomicBool::new(false);
+// Also set by the config watch, any reload goes through the SIGHUP handler
+pub static SIGHUP_FLAG: AtomicBool = AtomicBool::new(false);
+pub static CONNECTED_CLIENTS: AtomicUsize = AtomicUsize::new(0);
 
 extern "C" fn interrupt_handler(_: libc::c_int) {
     SIGHUP_FLAG.store(true, Ordering::SeqCst);
 }
 
-fn sighup_reload_config_init(requests: Sender<OrchestratorMsg>, logger: Logger) {
+fn sighup_reload_config_init(mut reload: ServerReload, logger: Logger) {
     unsafe {
         let mut sa: libc::sigaction = std::mem::zeroed();
         sa.sa_sigaction = interrupt_handler as usize;
@@ -55,27 +89,14 @@ fn sighup_reload_config_init(requests: Sender<OrchestratorMsg>, logger: Logger)
     thread::spawn(move || {
         loop {
             if SIGHUP_FLAG.swap(false, Ordering::SeqCst) {
-                let (tx, rx) = mpsc::channel();
-
                 logger::info!(logger, "SIGHUP handler: Reloading Configuration");
 
-                if let Err(err) = requests.send(OrchestratorMsg::Request(OrchestratorRequest {
-                    action: ServiceAction::Reload,
-                    response_channel: tx,
-                })) {
-                    logger::error!(logger, "SIGHUP handler: {err}");
+                let not_applied = reload.reload();
+                for part in &not_applied {
+                    logger::warn!(logger, "SIGHUP handler: Not applied: {part}");
                 }
-
-                for response in rx {
-                    logger::info!(
-                        logger,
-                        "SIGHUP handler: {}",
-                        match response {
-                            ResponsePart::Error(err) => err.to_string(),
-                            ResponsePart::Info(resp) => resp,
-                            ResponsePart::Stream(_) => "Wrong Response: Stream".to_string(),
-                        }
-                    )
+                if not_applied.is_empty() {
+                    logger::info!(logger, "SIGHUP handler: Configuration reloaded");
                 }
             }
             thread::sleep(Duration::from_millis(100));
@@ -83,39 +104,124 @@ fn sighup_reload_config_init(requests: Sender<OrchestratorMsg>, logger: Logger)
     });
 }
 
-fn command_to_action(req: Request) -> Option<ServiceAction> {
+// Value of a "--name=value" flag
+fn flag_value<'a>(flags: &'a [String], name: &str) -> Option<&'a str> {
+    flags.iter().find_map(|flag| {
+        flag.strip_prefix("--")
+            .and_then(|flag| flag.strip_prefix(name))
+            .and_then(|flag| flag.strip_prefix('='))
+    })
+}
+
+// A "--name" flag without value
+fn has_flag(flags: &[String], name: &str) -> bool {
+    flags
+        .iter()
+        .any(|flag| flag.strip_prefix("--") == Some(name))
+}
+
+fn parse_flag<T: std::str::FromStr>(flags: &[String], name: &str) -> Result<Option<T>, String> {
+    flag_value(flags, name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| format!("Invalid value for --{name}: {value}"))
+        })
+        .transpose()
+}
+
+fn parse_date_flag(flags: &[String], name: &str) -> Result<Option<SystemTime>, String> {
+    flag_value(flags, name)
+        .map(|value| {
+            logger::parse_rfc3339(value).ok_or(format!("Invalid date for --{name}: {value}"))
+        })
+        .transpose()
+}
+
+fn command_to_action(req: Request) -> Result<ServiceAction, String> {
     let alias = req.args.first().cloned().unwrap_or_default();
 
     if let Some(input) = req.stream {
-        return Some(ServiceAction::Input(alias, input));
+        return Ok(ServiceAction::Input(alias, input));
     }
 
     match req.command.as_str() {
-        "start" | "st" => Some(ServiceAction::Start(alias)),
-        "stop" | "sp" => Some(ServiceAction::Stop(alias)),
-        "restart" | "rs" => Some(ServiceAction::Restart(alias)),
-        "status" | "stat" => Some(ServiceAction::Status(alias)),
-        "attach" | "at" => Some(ServiceAction::Attach(alias)),
-        "detach" | "dt" => Some(ServiceAction::Detach(alias)),
-        "reload" | "rl" => Some(ServiceAction::Reload),
-        "list" | "ls" => Some(ServiceAction::List),
-        "help" | "?" => Some(ServiceAction::Help),
+        "start" | "st" => Ok(ServiceAction::Start(alias)),
+        "stop" | "sp" => Ok(ServiceAction::Stop(alias)),
+        "restart" | "rs" => match parse_flag(&req.flags, "batch")? {
+            Some(0) => Err("Invalid value for --batch: 0".to_string()),
+            Some(batch_size) => Ok(ServiceAction::RollingRestart(alias, batch_size)),
+            None if has_flag(&req.flags, "rolling") => Ok(ServiceAction::RollingRestart(alias, 1)),
+            None => Ok(ServiceAction::Restart(alias)),
+        },
+        "status" | "stat" => Ok(ServiceAction::Status(alias, has_flag(&req.flags, "config"))),
+        "attach" | "at" => Ok(ServiceAction::Attach(alias)),
+        "detach" | "dt" => Ok(ServiceAction::Detach(alias)),
+        "signal" | "sig" => {
+            let signal = req.args.get(1).ok_or("Missing signal")?;
+            let signal = signal
+                .parse::<i32>()
+                .ok()
+                .or_else(|| service::signal_from_str(signal))
+                .ok_or(format!("Invalid signal: {signal}"))?;
+            Ok(ServiceAction::Signal(alias, signal))
+        }
+        "pause" => Ok(ServiceAction::Pause(alias)),
+        "resume" => Ok(ServiceAction::Resume(alias)),
+        "reopen" | "ro" => Ok(ServiceAction::Reopen(alias)),
+        "history" | "hs" => Ok(ServiceAction::History(alias)),
+        "search" | "grep" => Ok(ServiceAction::Search(
+            alias,
+            Box::new(SearchQuery {
+                pattern: req.args.get(1..).unwrap_or_default().join(" "),
+                since: parse_date_flag(&req.flags, "since")?,
+                until: parse_date_flag(&req.flags, "until")?,
+                run: parse_flag(&req.flags, "run")?,
+                limit: parse_flag(&req.flags, "limit")?,
+            }),
+        )),
+        "top" => Ok(ServiceAction::Top(TopOptions {
+            sort: parse_flag(&req.flags, "sort")?.unwrap_or_default(),
+            interval: parse_flag(&req.flags, "interval")?,
+            count: parse_flag(&req.flags, "count")?,
+        })),
+        "events" | "subscribe" => Ok(ServiceAction::Events(req.args.first().cloned())),
+        "reload" | "rl" => match req.args.first() {
+            Some(alias) => Ok(ServiceAction::ReloadJob(alias.clone())),
+            None => Ok(ServiceAction::Reload(has_flag(&req.flags, "dry-run"))),
+        },
+        "scale" => {
+            let numprocs = req.args.get(1).ok_or("Missing number of jobs")?;
+            let numprocs = numprocs
+                .parse::<u16>()
+                .ok()
+                .filter(|numprocs| *numprocs > 0)
+                .ok_or(format!("Invalid number of jobs: {numprocs}"))?;
+            Ok(ServiceAction::Scale(
+                alias,
+                numprocs,
+                has_flag(&req.flags, "persist"),
+            ))
+        }
+        "check-config" | "check" => Ok(ServiceAction::CheckConfig),
+        "list" | "ls" => Ok(ServiceAction::List),
+        "help" | "?" => Ok(ServiceAction::Help),
         "stop_server" => std::process::exit(0),
-        _ => None,
+        _ => Err("Command not found".to_string()),
     }
 }
 
 fn process_request(
     req: Request,
-    requests_tx: Sender<OrchestratorMsg>,
+    requests_tx: OrchestratorSender,
     mut socket_tx: TcpStream,
 ) -> Result<(), Box<dyn Error>> {
-    let Some(action) = command_to_action(req) else {
-        socket_tx.write(
-            serde_json::to_string(&[ResponsePart::Error(format!("Command not found",))])?
-                .as_bytes(),
-        )?;
-        return Ok(());
+    let action = match command_to_action(req) {
+        Ok(action) => action,
+        Err(err) => {
+            socket_tx.write(serde_json::to_string(&[ResponsePart::Error(err)])?.as_bytes())?;
+            return Ok(());
+        }
     };
 
     let (tx, rx) = mpsc::channel();
@@ -126,7 +232,7 @@ fn process_request(
     }))?;
 
     match action {
-        ServiceAction::Attach(_) => {
+        ServiceAction::Attach(_) | ServiceAction::Top(_) | ServiceAction::Events(_) => {
             thread::spawn(move || -> io::Result<()> {
                 for data in rx {
                     socket_tx.write(serde_json::to_string(&[data])?.as_bytes())?;
@@ -146,9 +252,32 @@ fn process_request(
     Ok(())
 }
 
+fn serve_client(
+    sock_read: &TcpStream,
+    requests_tx: OrchestratorSender,
+    logger: &Logger,
+) -> io::Result<()> {
+    let deserializer = Deserializer::from_reader(sock_read).into_iter::<Request>();
+
+    for req in deserializer {
+        let Ok(req) = req else {
+            logger::warn!(logger, "Deserializing {req:?}");
+            continue;
+        };
+
+        logger::info!(logger, "{req:?}");
+
+        if let Err(err) = process_request(req, requests_tx.clone(), sock_read.try_clone()?) {
+            logger::error!(logger, "Processing request: {err}");
+        }
+    }
+
+    Ok(())
+}
+
 fn startup_services(
     services: &Vec<String>,
-    requests_tx: Sender<OrchestratorMsg>,
+    requests_tx: OrchestratorSender,
 ) -> Result<(), Box<dyn Error>> {
     for service in services {
         let (tx, rx) = mpsc::channel();
@@ -175,16 +304,31 @@ fn main() -> Result<(), Box<dyn Error>> {
     let (cfg_path, args) = dir_utils::parse_config_path();
     let mut config = Config::load(cfg_path)?;
 
+    // Only check the service files and exit, nothing is started
+    if dir_utils::has_flag("check-config") {
+        let (files, problems) = check::check_services(&config.service_sources());
+        for problem in &problems {
+            println!("{problem}");
+        }
+        println!("{} problems found in {files} files", problems.len());
+        std::process::exit(if problems.is_empty() { 0 } else { 1 });
+    }
+
     let logger = Logger::new(config.log_level.clone(), config.logs.clone(), config.syslog)?;
 
+    let addr_fixed = args.is_some();
     if let Some(arg) = args {
         config.server_addr = arg.parse()?;
     }
 
     let (orchestrator, requests_tx) = Orchestrator::new(
-        Services::new(config.get_includes().clone())?,
+        Services::new(config.service_sources())?,
         logger.clone(),
+        config.backpressure.clone(),
+        config.journal.clone(),
+        config.history.clone(),
     );
+    let io_counters = orchestrator.io_counters.clone();
 
     // TODO: manage clean exit by taking the handle
     thread::spawn(move || {
@@ -194,34 +338,57 @@ fn main() -> Result<(), Box<dyn Error>> {
     // Start the services in init
     startup_services(&config.start.services, requests_tx.clone())?;
 
+    // Serve metrics if asked for
+    if let Some(addr) = config.metrics {
+        metrics::serve(addr, requests_tx.clone(), io_counters, logger.clone())?;
+    }
+
+    // Reload on changes of the config files if asked for
+    if config.watch_config {
+        config_watch::watch(&config, logger.clone())?;
+    }
+
+    // Not blocking, so a new listener from a reload is picked between accepts
+    let mut listen_sock: TcpListener = TcpListener::bind(config.server_addr)?;
+    listen_sock.set_nonblocking(true)?;
+    let (listener_tx, listener_rx) = mpsc::channel();
+
     // Handle sighup signal
-    sighup_reload_config_init(requests_tx.clone(), logger.clone());
+    sighup_reload_config_init(
+        ServerReload::new(
+            config,
+            addr_fixed,
+            requests_tx.clone(),
+            listener_tx,
+            logger.clone(),
+        ),
+        logger.clone(),
+    );
 
-    let listen_sock: TcpListener = TcpListener::bind(config.server_addr)?;
     let mut handlers = Vec::new();
     loop {
-        let sock_read: TcpStream = listen_sock.accept()?.0;
+        if let Ok(new_sock) = listener_rx.try_recv() {
+            listen_sock = new_sock;
+            listen_sock.set_nonblocking(true)?;
+        }
+
+        let sock_read: TcpStream = match listen_sock.accept() {
+            Ok((sock_read, _)) => sock_read,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        sock_read.set_nonblocking(false)?;
         let requests_tx = requests_tx.clone();
         let logger = logger.clone();
 
         let handle = thread::spawn(move || -> io::Result<()> {
-            let deserializer = Deserializer::from_reader(&sock_read).into_iter::<Request>();
-
-            for req in deserializer {
-                let Ok(req) = req else {
-                    logger::warn!(logger, "Deserializing {req:?}");
-                    continue;
-                };
-
-                logger::info!(logger, "{req:?}");
-
-                if let Err(err) = process_request(req, requests_tx.clone(), sock_read.try_clone()?)
-                {
-                    logger::error!(logger, "Processing request: {err}");
-                }
-            }
-
-            Ok(())
+            CONNECTED_CLIENTS.fetch_add(1, Ordering::SeqCst);
+            let result = serve_client(&sock_read, requests_tx, &logger);
+            CONNECTED_CLIENTS.fetch_sub(1, Ordering::SeqCst);
+            result
         });
 
         handlers.push(handle);
@@ -0,0 +1,327 @@
+// Note: the collection is part of the orchestrator like jobs and events, so
+// it reads the jobs from its own loop. Only the HTTP side lives in its thread,
+// and the I/O counters are read there from the router without a round trip.
+
+use logger::{LogLevel, Logger};
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddrV4, TcpListener, TcpStream},
+    sync::{atomic::Ordering, mpsc},
+    thread,
+    time::Duration,
+};
+use taskmeister::ResponsePart;
+
+use crate::{
+    CONNECTED_CLIENTS,
+    io_router::IoCounters,
+    jobs::{ExitReason, JobStatus},
+    orchestrate::{Orchestrator, OrchestratorMsg, OrchestratorRequest, OrchestratorSender},
+    resources::ProcUsage,
+    service::ServiceAction,
+};
+
+// Time a scraper has to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Name, help and value of the metrics sampled for every process
+type UsageFamily = (&'static str, &'static str, fn(&ProcUsage) -> f64);
+
+/// Serves /metrics in the Prometheus text format. Each scrape is a request to
+/// the orchestrator, so the loop is never blocked by a slow scraper
+pub fn serve(
+    addr: SocketAddrV4,
+    requests: OrchestratorSender,
+    counters: IoCounters,
+    logger: Logger,
+) -> Result<(), io::Error> {
+    let listener = TcpListener::bind(addr)?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Err(err) = stream.and_then(|stream| respond(stream, &requests, &counters)) {
+                logger::warn!(logger, "Metrics: {err}");
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn respond(
+    mut stream: TcpStream,
+    requests: &OrchestratorSender,
+    counters: &IoCounters,
+) -> Result<(), io::Error> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Headers are not needed, just consume them up to the blank line
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+
+    let mut request = request_line.split_whitespace();
+    let (status, body) = match (request.next(), request.next()) {
+        (Some("GET"), Some(path)) if path.split('?').next() == Some("/metrics") => {
+            match collect(requests, counters) {
+                Ok(body) => ("200 OK", body),
+                Err(err) => ("503 Service Unavailable", format!("{err}\n")),
+            }
+        }
+        (Some("GET"), _) => ("404 Not Found", "Not Found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method Not Allowed\n".to_string()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+fn collect(requests: &OrchestratorSender, counters: &IoCounters) -> Result<String, String> {
+    let (tx, rx) = mpsc::channel();
+
+    requests
+        .send(OrchestratorMsg::Request(OrchestratorRequest {
+            action: ServiceAction::Metrics,
+            response_channel: tx,
+        }))
+        .map_err(|err| err.to_string())?;
+
+    let mut body = String::new();
+    for response in rx {
+        match response {
+            ResponsePart::Info(metrics) => body += &metrics,
+            ResponsePart::Error(err) => return Err(err),
+            ResponsePart::Stream(_) => (),
+        }
+    }
+
+    // Copied so the router is not held while formatting
+    let mut counters: Vec<_> = counters
+        .lock()
+        .map(|counters| counters.clone().into_iter().collect())
+        .unwrap_or_default();
+    counters.sort();
+    family(
+        &mut body,
+        "taskmeister_io_bytes_forwarded_total",
+        "counter",
+        "Bytes read by the I/O router from the output of the jobs",
+        counters.iter().flat_map(|(alias, (stdout, stderr))| {
+            [
+                (
+                    labels(&[("alias", alias), ("stream", "stdout")]),
+                    *stdout as f64,
+                ),
+                (
+                    labels(&[("alias", alias), ("stream", "stderr")]),
+                    *stderr as f64,
+                ),
+            ]
+        }),
+    );
+
+    // Server wide gauges are kept outside of the orchestrator
+    family(
+        &mut body,
+        "taskmeister_connected_clients",
+        "gauge",
+        "Clients connected to the server",
+        [(
+            String::new(),
+            CONNECTED_CLIENTS.load(Ordering::SeqCst) as f64,
+        )],
+    );
+    family(
+        &mut body,
+        "taskmeister_orchestrator_queue_depth",
+        "gauge",
+        "Messages waiting to be handled by the orchestrator",
+        [(String::new(), requests.queued() as f64)],
+    );
+
+    Ok(body)
+}
+
+impl Orchestrator {
+    /// Metrics of every service and its jobs in the Prometheus text format
+    pub fn metrics(&self) -> String {
+        let services = self.get_services().sorted();
+        let mut body = String::new();
+
+        let mut states = Vec::new();
+        let mut up = Vec::new();
+        let mut healthy = Vec::new();
+        let mut restarts = Vec::new();
+        let mut exit_codes = Vec::new();
+        let mut processes = Vec::new();
+
+        for service in &services {
+            let alias = labels(&[("alias", &service.alias)]);
+            let job = self.jobs.get(&service.alias);
+            let status = job.map(|job| &job.status);
+
+            states.push((
+                labels(&[
+                    ("alias", &service.alias),
+                    ("state", status.map_or("not_started", JobStatus::name)),
+                ]),
+                1.0,
+            ));
+            up.push((
+                alias.clone(),
+                matches!(
+                    status,
+                    Some(
+                        JobStatus::Starting
+                            | JobStatus::Running(_)
+                            | JobStatus::Stopping
+                            | JobStatus::TimedOut
+                            | JobStatus::Paused
+                    )
+                ) as u8 as f64,
+            ));
+            healthy.push((
+                alias.clone(),
+                matches!(status, Some(JobStatus::Running(true))) as u8 as f64,
+            ));
+            restarts.push((alias.clone(), job.map_or(0, |job| job.restarts) as f64));
+
+            if let Some(exit) = job.and_then(|job| job.last_exit.as_ref()) {
+                exit_codes.push((alias.clone(), exit_code(exit) as f64));
+            }
+
+            for (pid, usage) in self.get_usage(&service.alias).unwrap_or_default() {
+                if let Some(usage) = usage {
+                    processes.push((
+                        labels(&[("alias", &service.alias), ("pid", &pid.to_string())]),
+                        usage,
+                    ));
+                }
+            }
+        }
+
+        family(
+            &mut body,
+            "taskmeister_service_state",
+            "gauge",
+            "Current state of the job of each service",
+            states,
+        );
+        family(
+            &mut body,
+            "taskmeister_service_up",
+            "gauge",
+            "Whether the job has a process alive",
+            up,
+        );
+        family(
+            &mut body,
+            "taskmeister_service_healthy",
+            "gauge",
+            "Whether the job outlived its start time",
+            healthy,
+        );
+        family(
+            &mut body,
+            "taskmeister_service_restarts_total",
+            "counter",
+            "Starts of the job not asked by a start command",
+            restarts,
+        );
+        family(
+            &mut body,
+            "taskmeister_service_last_exit_code",
+            "gauge",
+            "Exit code of the last run, 128 plus the signal when killed by one",
+            exit_codes,
+        );
+
+        let usage_families: [UsageFamily; 5] = [
+            (
+                "taskmeister_process_uptime_seconds",
+                "Seconds since the process started",
+                |usage| usage.uptime.as_secs_f64(),
+            ),
+            (
+                "taskmeister_process_cpu_percent",
+                "CPU used since the previous sample, 100 is one full core",
+                |usage| usage.cpu_percent,
+            ),
+            (
+                "taskmeister_process_resident_memory_bytes",
+                "Resident set size of the process",
+                |usage| usage.rss as f64,
+            ),
+            (
+                "taskmeister_process_open_fds",
+                "File descriptors open by the process",
+                |usage| usage.fds as f64,
+            ),
+            (
+                "taskmeister_process_threads",
+                "Threads of the process",
+                |usage| usage.threads as f64,
+            ),
+        ];
+        for (name, help, value) in usage_families {
+            family(
+                &mut body,
+                name,
+                "gauge",
+                help,
+                processes
+                    .iter()
+                    .map(|(labels, usage)| (labels.clone(), value(usage))),
+            );
+        }
+
+        body
+    }
+}
+
+// #################### UTILS ####################
+
+fn family(
+    body: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (String, f64)>,
+) {
+    let _ = writeln!(body, "# HELP {name} {help}\n# TYPE {name} {kind}");
+    for (labels, value) in samples {
+        let _ = writeln!(body, "{name}{labels} {value}");
+    }
+}
+
+fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs: Vec<String> = pairs
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect();
+
+    format!("{{{}}}", pairs.join(","))
+}
+
+// Same convention as the shells
+fn exit_code(exit: &ExitReason) -> i32 {
+    match exit {
+        ExitReason::Code(code) => *code,
+        ExitReason::Signal { signal, .. } => 128 + signal,
+    }
+}
@@ -1,9 +1,13 @@
 use crate::{
     CLI_HELP,
-    events::JobEvent,
-    io_router::{self, IoRouterRequest},
+    events::{HookEvent, JobEvent, Subscriber, WatchdogEvent},
+    history::{History, HistoryConfig, RunTrigger},
+    io_router::{self, BackpressurePolicy, IoCounters, IoRouterRequest},
     jobs::{Job, JobFlags, JobStatus},
-    service::{Service, ServiceAction, Services},
+    journal::JournalConfig,
+    resources::ProcUsage,
+    rollout::Rollout,
+    service::{Service, ServiceAction, ServiceChange, Services},
     watcher::{self, Watched},
 };
 use logger::{LogLevel, Logger};
@@ -13,13 +17,17 @@ use std::{
     process::ChildStdin,
     sync::{
         Arc, Mutex,
-        mpsc::{self, Receiver, Sender},
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SendError, Sender},
     },
     thread,
     time::Duration,
 };
 use taskmeister::ResponsePart;
 
+// How often the rollouts are checked when no message comes
+const ROLLOUT_CHECK: Duration = Duration::from_secs(1);
+
 #[derive(Debug)]
 pub enum OrchestratorError {
     ServiceNotFound,
@@ -28,11 +36,19 @@ pub enum OrchestratorError {
     ServiceAlreadyStarted,
     ServiceAlreadyStopping,
     JobNotFound,
+    JobNotRunning,
+    JobNotPaused,
+    ReloadNotConfigured,
+    ScaleInstance,
+    RolloutInProgress,
+    StartCancelled,
+    Failed(Vec<(String, OrchestratorError)>), // Alias and its error, the rest went on
     JobHasNoIoHandle,
     JobAlreadyAttached,
     InternalChannelSendError,
     InternalChannelReceiveError,
     JobIoError(io::Error),
+    HookFailed(&'static str, io::Error), // Hook name, Error
 }
 
 impl fmt::Display for OrchestratorError {
@@ -46,7 +62,34 @@ impl fmt::Display for OrchestratorError {
             OrchestratorError::ServiceAlreadyStarted => write!(f, "Service already started"),
             OrchestratorError::ServiceAlreadyStopping => write!(f, "Service already stopping"),
             OrchestratorError::JobNotFound => write!(f, "Job not found"),
+            OrchestratorError::JobNotRunning => write!(f, "Job is not running"),
+            OrchestratorError::JobNotPaused => write!(f, "Job is not paused"),
+            OrchestratorError::ReloadNotConfigured => {
+                write!(f, "Service has no reload_signal or reload_cmd")
+            }
+            OrchestratorError::ScaleInstance => {
+                write!(
+                    f,
+                    "Alias is one of the jobs of a service, scale the service"
+                )
+            }
+            OrchestratorError::RolloutInProgress => {
+                write!(f, "A rolling restart of the service is in progress")
+            }
+            OrchestratorError::StartCancelled => {
+                write!(f, "Stopped before its pre_start hook was done")
+            }
+            OrchestratorError::Failed(errors) => {
+                let errors: Vec<String> = errors
+                    .iter()
+                    .map(|(alias, error)| format!("{alias}: {error}"))
+                    .collect();
+                write!(f, "Failed for {}", errors.join("; "))
+            }
             OrchestratorError::JobIoError(error) => write!(f, "Job I/O error: {}", error),
+            OrchestratorError::HookFailed(hook, error) => {
+                write!(f, "Hook {} failed: {}", hook, error)
+            }
             OrchestratorError::JobHasNoIoHandle => {
                 write!(f, "Job has no handle for either stdin/stdout/stderr")
             }
@@ -68,37 +111,82 @@ pub struct OrchestratorRequest {
 pub enum OrchestratorMsg {
     Request(OrchestratorRequest),
     Event(JobEvent),
+    Watchdog(WatchdogEvent),
+    Hook(HookEvent),
+}
+
+/// Sender of orchestrator messages that keeps count of the ones not yet handled
+#[derive(Clone)]
+pub struct OrchestratorSender {
+    tx: Sender<OrchestratorMsg>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl OrchestratorSender {
+    pub fn send(&self, message: OrchestratorMsg) -> Result<(), SendError<OrchestratorMsg>> {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        self.tx
+            .send(message)
+            .inspect_err(|_| _ = self.queued.fetch_sub(1, Ordering::SeqCst))
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
 }
 
 pub struct Orchestrator {
     services: Services,
+    pub journal: Option<JournalConfig>,
+    pub history: History,
     pub logger: Logger,
     pub jobs: HashMap<String, Job>,
     pub watched: Arc<Mutex<HashMap<String, Vec<Watched>>>>,
-    messages_tx: Sender<OrchestratorMsg>,
+    pub subscribers: Vec<Subscriber>,
+    pub rollouts: Vec<Rollout>,
+    pub messages_tx: OrchestratorSender,
     messages_rx: Receiver<OrchestratorMsg>,
     pub io_router_requests: Sender<IoRouterRequest>,
+    pub io_counters: IoCounters,
 }
 
 impl Orchestrator {
-    pub fn new(services: Services, logger: Logger) -> (Orchestrator, Sender<OrchestratorMsg>) {
+    pub fn new(
+        services: Services,
+        logger: Logger,
+        backpressure: BackpressurePolicy,
+        journal: Option<JournalConfig>,
+        history: Option<HistoryConfig>,
+    ) -> (Orchestrator, OrchestratorSender) {
         let (tx, rx) = mpsc::channel();
+        let tx = OrchestratorSender {
+            tx,
+            queued: Arc::new(AtomicUsize::new(0)),
+        };
         let (io_tx, io_rx) = mpsc::channel();
         let io_logger = logger.clone();
+        let io_journal = journal.clone();
+        let io_counters = IoCounters::default();
+        let router_counters = io_counters.clone();
 
         thread::spawn(move || {
-            io_router::route(io_rx, io_logger);
+            io_router::route(io_rx, io_logger, backpressure, io_journal, router_counters);
         });
 
         (
             Orchestrator {
                 services,
+                history: History::new(history, journal.clone()),
+                journal,
                 logger,
                 jobs: HashMap::new(),
                 watched: Arc::new(Mutex::new(HashMap::new())),
+                subscribers: Vec::new(),
+                rollouts: Vec::new(),
                 messages_tx: tx.clone(),
                 messages_rx: rx,
                 io_router_requests: io_tx,
+                io_counters,
             },
             tx,
         )
@@ -111,7 +199,11 @@ impl Orchestrator {
 
     pub fn set_job_status(&mut self, alias: &str, status: JobStatus) {
         if let Some(job) = self.jobs.get_mut(alias) {
-            job.status = status;
+            let previous = std::mem::replace(&mut job.status, status.clone());
+
+            if previous != status {
+                self.publish(alias, &previous, &status);
+            }
         }
     }
 
@@ -173,12 +265,275 @@ impl Orchestrator {
             .collect())
     }
 
+    // Pids of the job with the resources sampled last for each one
+    pub fn get_usage(
+        &self,
+        alias: &str,
+    ) -> Result<Vec<(u32, Option<ProcUsage>)>, OrchestratorError> {
+        Ok(self
+            .watched
+            .lock()
+            .unwrap()
+            .get(alias)
+            .ok_or(OrchestratorError::JobNotFound)?
+            .iter()
+            .map(|watched_job| (watched_job.process.id(), watched_job.sampler.usage.clone()))
+            .collect())
+    }
+
+    /// Reloads the service files and applies the changes to the jobs. The new
+    /// config is in place already, so a failing alias does not stop the others
+    pub fn reload_services(&mut self) -> Result<(), OrchestratorError> {
+        match self.services.update() {
+            Ok(changes) => {
+                let mut failed = Vec::new();
+                // Jobs of numprocs services to restart, by service
+                let mut rolling: Vec<(String, Vec<String>)> = Vec::new();
+
+                for (alias, change) in changes {
+                    if change != ServiceChange::Unchanged {
+                        logger::info!(self.logger, "[Reload] [{alias}] {change}");
+                    }
+                    let res = match change {
+                        ServiceChange::Added(true) => {
+                            self.start_request(&alias, RunTrigger::Manual, None)
+                        }
+                        // New jobs of a numprocs service run along the others
+                        ServiceChange::Added(false) => match self.services.get(&alias) {
+                            Some(service)
+                                if service.instance > 0
+                                    && self.service_running(service.service_alias()) =>
+                            {
+                                self.start_request(&alias, RunTrigger::Manual, None)
+                            }
+                            _ => Ok(()),
+                        },
+                        ServiceChange::Unchanged => Ok(()),
+                        ServiceChange::Update(_) => {
+                            self.update_watched_limits(&alias);
+                            Ok(())
+                        }
+                        ServiceChange::Restart(_) => {
+                            let service_alias = self
+                                .services
+                                .get(&alias)
+                                .filter(|service| service.numprocs > 1 || service.instance > 0)
+                                .map(|service| service.service_alias().to_string());
+
+                            // Restarted a job at a time once all are known
+                            if let Some(service_alias) = service_alias {
+                                match rolling
+                                    .iter_mut()
+                                    .find(|(other, _)| *other == service_alias)
+                                {
+                                    Some((_, jobs)) => jobs.push(alias.clone()),
+                                    None => rolling.push((service_alias, vec![alias.clone()])),
+                                }
+                                continue;
+                            }
+
+                            match self.stop_request(&alias, false, true) {
+                                // Service stopped error on restart is ok satus
+                                Err(OrchestratorError::ServiceStopped)
+                                | Err(OrchestratorError::JobNotFound)
+                                | Ok(_) => Ok(()),
+                                err => err,
+                            }
+                        }
+                        ServiceChange::Reload(_) => {
+                            self.update_watched_limits(&alias);
+                            match self.reload_request(&alias) {
+                                // Jobs not running pick the changes on start
+                                Err(OrchestratorError::JobNotRunning)
+                                | Err(OrchestratorError::JobNotFound)
+                                | Ok(_) => Ok(()),
+                                err => err,
+                            }
+                        }
+                        ServiceChange::Removed => self.remove_request(&alias),
+                    };
+
+                    if let Err(err) = res {
+                        logger::error!(self.logger, "[Reload] [{alias}] {err}");
+                        failed.push((alias, err));
+                    }
+                }
+
+                for (alias, jobs) in rolling {
+                    // One in progress takes them, they restart with the new config
+                    if self.extend_rollout(&alias, &jobs) {
+                        continue;
+                    }
+                    match self.start_rollout(&alias, jobs, 1, None) {
+                        // None of the jobs runs
+                        Err(OrchestratorError::ServiceStopped) | Ok(_) => (),
+                        Err(err) => {
+                            logger::error!(self.logger, "[Reload] [{alias}] {err}");
+                            failed.push((alias, err));
+                        }
+                    }
+                }
+
+                match failed.is_empty() {
+                    true => Ok(()),
+                    false => Err(OrchestratorError::Failed(failed)),
+                }
+            }
+            Err(err) => {
+                logger::error!(self.logger, "Updating services: {err}");
+                Err(OrchestratorError::ServiceUpdate(err))
+            }
+        }
+    }
+
+    /// Grows or shrinks a numprocs service. New jobs are started if the
+    /// service is running, the highest ones are stopped gracefully
+    pub fn scale_request(
+        &mut self,
+        alias: &str,
+        numprocs: u16,
+        persist: bool,
+    ) -> Result<String, OrchestratorError> {
+        let service = self
+            .services
+            .get(alias)
+            .ok_or(OrchestratorError::ServiceNotFound)?;
+        if service.instance > 0 {
+            return Err(OrchestratorError::ScaleInstance);
+        }
+        let old_numprocs = service.numprocs;
+        let running = self.service_running(alias);
+
+        let (added, removed) = self
+            .services
+            .scale(alias, numprocs, persist)
+            .map_err(OrchestratorError::ServiceUpdate)?;
+        logger::info!(
+            self.logger,
+            "[Scale] [{alias}] numprocs {old_numprocs} -> {numprocs}"
+        );
+
+        // The services are changed already, so every job is tried
+        let mut failed = Vec::new();
+        if running {
+            for instance in added {
+                if let Err(err) = self.start_request(&instance, RunTrigger::Manual, None) {
+                    logger::error!(self.logger, "[Scale] [{instance}] {err}");
+                    failed.push((instance, err));
+                }
+            }
+        }
+        for instance in removed {
+            if let Err(err) = self.remove_request(&instance) {
+                logger::error!(self.logger, "[Scale] [{instance}] {err}");
+                failed.push((instance, err));
+            }
+        }
+        if !failed.is_empty() {
+            return Err(OrchestratorError::Failed(failed));
+        }
+
+        Ok(format!(
+            "{alias}: numprocs {old_numprocs} -> {numprocs}{}",
+            if persist { ", saved in its file" } else { "" }
+        ))
+    }
+
+    // If any job of the service is starting or running
+    fn service_running(&self, alias: &str) -> bool {
+        let Some(service) = self.services.get(alias) else {
+            return false;
+        };
+
+        taskmeister::generate_alias_names(alias, service.numprocs).any(|instance| {
+            matches!(
+                self.get_job_status(&instance),
+                Some(JobStatus::Starting | JobStatus::Running(_))
+            )
+        })
+    }
+
+    // Starts every job of the service. True when some wait for their
+    // pre_start hook, they tell the client once it ran
+    fn start_service(
+        &mut self,
+        alias: &str,
+        response: &Sender<ResponsePart>,
+    ) -> Result<bool, OrchestratorError> {
+        let service = self
+            .services
+            .get(alias)
+            .ok_or(OrchestratorError::ServiceNotFound)?;
+
+        let mut waiting = false;
+        for instance in taskmeister::generate_alias_names(alias, service.numprocs) {
+            self.reset_job_retries(&instance);
+            self.start_request(&instance, RunTrigger::Manual, Some(response.clone()))?;
+            waiting |= self
+                .jobs
+                .get(&instance)
+                .is_some_and(|job| job.hook.is_some());
+        }
+
+        Ok(waiting)
+    }
+
+    // Stops the job and removes its service
+    fn remove_request(&mut self, alias: &str) -> Result<(), OrchestratorError> {
+        match self.stop_request(alias, true, false) {
+            Ok(_) => {
+                // The only place where a job is removed is when reloading or
+                // scaling since we will loose track of its status. A stopping
+                // job is kept for its exit event, that removes the service
+                if self.get_job_status(alias) != Some(JobStatus::Stopping) {
+                    self.remove_job(alias);
+                }
+                Ok(())
+            }
+            Err(OrchestratorError::JobNotFound) | Err(OrchestratorError::ServiceStopped) => {
+                // The job was never started
+                self.remove_job(alias);
+                self.remove_service(alias);
+                Ok(())
+            }
+            err => err,
+        }
+    }
+
     // #################### UTILS ####################
 
+    // Waits for the next message. While a rollout is in progress the wait is
+    // cut now and then, so a batch that never gets healthy times out
+    fn next_message(&mut self) -> Option<OrchestratorMsg> {
+        loop {
+            if self.rollouts.is_empty() {
+                return self.messages_rx.recv().ok();
+            }
+
+            match self.messages_rx.recv_timeout(ROLLOUT_CHECK) {
+                Ok(message) => return Some(message),
+                Err(RecvTimeoutError::Timeout) => self.advance_rollouts(),
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+
     pub fn remove_watched(&self, alias: &str) -> Option<Vec<Watched>> {
         self.watched.lock().unwrap().remove(alias)
     }
 
+    // Applies the watchdog of the service to the processes already running
+    pub fn update_watched_limits(&self, alias: &str) {
+        let Some(service) = self.services.get(alias) else {
+            return;
+        };
+        if let Some(watched_jobs) = self.watched.lock().unwrap().get_mut(alias) {
+            for job in watched_jobs {
+                job.limits.set(service.watchdog.clone());
+            }
+        }
+    }
+
     pub fn remove_watched_timeout(&self, alias: &str) {
         if let Some(watched_jobs) = self.watched.lock().unwrap().get_mut(alias) {
             for job in watched_jobs {
@@ -205,81 +560,71 @@ impl Orchestrator {
         // If not job structure should be protecetd by mutex. This way only watched needs
         // protection since watcher also access the structure (in fact is the one
         // that consumes most of the lock time)
-        while let Some(message) = self.messages_rx.iter().next() {
+        while let Some(message) = self.next_message() {
+            self.messages_tx.queued.fetch_sub(1, Ordering::SeqCst);
+
             match message {
                 OrchestratorMsg::Request(request) => {
                     let result: ResponsePart = match request.action {
-                        ServiceAction::Start(alias) => match self.services.get(&alias) {
-                            Some(service) => {
-                                taskmeister::generate_alias_names(&alias, service.numprocs)
-                                    .try_for_each(|new_alias| {
-                                        self.reset_job_retries(&new_alias);
-                                        self.start_request(&new_alias)
-                                    })
-                                    .into()
-                            }
-                            None => {
-                                Err::<(), OrchestratorError>(OrchestratorError::ServiceNotFound)
-                                    .into()
+                        ServiceAction::Start(alias) => {
+                            match self.start_service(&alias, &request.response_channel) {
+                                // The jobs waiting for their pre_start hook answer once it ran
+                                Ok(true) => continue,
+                                res => res.map(|_| ()).into(),
                             }
-                        },
+                        }
                         ServiceAction::Restart(alias) => {
                             self.reset_job_retries(&alias);
                             self.stop_request(&alias, false, true).into()
                         }
+                        ServiceAction::RollingRestart(alias, batch_size) => {
+                            match self.rolling_restart(
+                                &alias,
+                                batch_size,
+                                Some(request.response_channel.clone()),
+                            ) {
+                                // The rollout answers once it is over
+                                Ok(_) => continue,
+                                Err(err) => Err::<(), OrchestratorError>(err).into(),
+                            }
+                        }
                         ServiceAction::Stop(alias) => {
                             self.stop_request(&alias, false, false).into()
                         }
-                        ServiceAction::Reload => match self.services.update() {
-                            Ok(up_services) => {
-                                let mut res = Ok(());
-                                for service in up_services {
-                                    res = match service {
-                                        ServiceAction::Start(alias) => self.start_request(&alias),
-                                        ServiceAction::Restart(alias) => {
-                                            match self.stop_request(&alias, false, true) {
-                                                // Service stopped error on restart is ok satus
-                                                Err(OrchestratorError::ServiceStopped)
-                                                | Err(OrchestratorError::JobNotFound)
-                                                | Ok(_) => Ok(()),
-                                                err => err,
-                                            }
-                                        }
-                                        ServiceAction::Stop(alias) => {
-                                            // Stop and remove service
-                                            match self.stop_request(&alias, true, false) {
-                                                Ok(_) => {
-                                                    // The only place where a job is removed is
-                                                    // when reloading since we will loose track
-                                                    // of its status
-                                                    self.remove_job(&alias);
-                                                    Ok(())
-                                                }
-                                                Err(OrchestratorError::JobNotFound) => {
-                                                    // If job is not found, service was
-                                                    // never started
-                                                    self.remove_service(&alias);
-                                                    Ok(())
-                                                }
-                                                err => err,
-                                            }
-                                        }
-                                        _ => Ok(()),
-                                    };
-
-                                    if res.is_err() {
-                                        break;
-                                    }
+                        ServiceAction::Reload(true) => match self.services.plan() {
+                            Ok(changes) => Ok::<String, OrchestratorError>(
+                                changes.iter().fold(String::new(), |acc, (alias, change)| {
+                                    acc + &format!("{alias}: {change}\n")
+                                }),
+                            ),
+                            Err(err) => Err(OrchestratorError::ServiceUpdate(err)),
+                        }
+                        .into(),
+                        ServiceAction::Reload(false) => self.reload_services().into(),
+                        ServiceAction::Sources(sources) => {
+                            // New sources from the server config, kept once they load
+                            // even if some jobs then fail to start or stop
+                            let previous = self.services.set_sources(*sources);
+                            match self.reload_services() {
+                                Err(err @ OrchestratorError::ServiceUpdate(_)) => {
+                                    self.services.set_sources(previous);
+                                    Err::<(), OrchestratorError>(err).into()
                                 }
-                                res
-                            }
-                            Err(err) => {
-                                logger::error!(self.logger, "Updating services: {err}");
-                                Err(OrchestratorError::ServiceUpdate(err))
+                                Err(err) => {
+                                    request
+                                        .response_channel
+                                        .send(ResponsePart::Info("Sources loaded".to_string()))
+                                        .ok();
+                                    Err::<(), OrchestratorError>(err).into()
+                                }
+                                Ok(_) => ResponsePart::Info("Sources loaded".to_string()),
                             }
                         }
-                        .into(),
-                        ServiceAction::Status(alias) => self.job_status(&alias).into(),
+                        ServiceAction::CheckConfig => {
+                            Ok::<String, OrchestratorError>(self.services.check()).into()
+                        }
+                        ServiceAction::Status(alias, false) => self.job_status(&alias).into(),
+                        ServiceAction::Status(alias, true) => self.service_config(&alias).into(),
                         ServiceAction::Help => {
                             Ok::<String, OrchestratorError>(CLI_HELP.to_string()).into()
                         }
@@ -293,7 +638,17 @@ impl Orchestrator {
                                 continue;
                             }
                         }
+                        ServiceAction::Signal(alias, signal) => {
+                            self.signal_request(&alias, signal).into()
+                        }
+                        ServiceAction::ReloadJob(alias) => self.reload_request(&alias).into(),
+                        ServiceAction::Scale(alias, numprocs, persist) => {
+                            self.scale_request(&alias, numprocs, persist).into()
+                        }
+                        ServiceAction::Pause(alias) => self.pause_request(&alias).into(),
+                        ServiceAction::Resume(alias) => self.resume_request(&alias).into(),
                         ServiceAction::Detach(alias) => self.detach_job(&alias).into(),
+                        ServiceAction::Reopen(alias) => self.reopen_job_output(&alias).into(),
                         ServiceAction::Input(alias, input) => {
                             if let Err(err) = self.forward_stdin_job(&alias, input) {
                                 Err::<(), OrchestratorError>(err).into()
@@ -303,6 +658,29 @@ impl Orchestrator {
                             }
                         }
                         ServiceAction::List => ResponsePart::Info(self.list_services()),
+                        ServiceAction::Events(alias) => {
+                            self.subscribe(alias, request.response_channel);
+                            // Changes are streamed as they happen
+                            continue;
+                        }
+                        ServiceAction::Metrics => ResponsePart::Info(self.metrics()),
+                        ServiceAction::History(alias) => match self.get_services().get(&alias) {
+                            Some(_) => ResponsePart::Info(self.history.describe(&alias)),
+                            None => {
+                                Err::<(), OrchestratorError>(OrchestratorError::ServiceNotFound)
+                                    .into()
+                            }
+                        },
+                        ServiceAction::Top(options) => {
+                            self.top_jobs(options, request.response_channel);
+                            // The top thread streams on its own
+                            continue;
+                        }
+                        ServiceAction::Search(alias, query) => {
+                            self.search_job_output(&alias, *query, request.response_channel);
+                            // The search thread answers on its own
+                            continue;
+                        }
                     };
 
                     request
@@ -313,7 +691,15 @@ impl Orchestrator {
                         })
                         .ok();
                 }
-                OrchestratorMsg::Event(event) => self.manage_event(event),
+                OrchestratorMsg::Event(event) => {
+                    self.manage_event(event);
+                    self.advance_rollouts();
+                }
+                OrchestratorMsg::Watchdog(event) => self.manage_watchdog_event(event),
+                OrchestratorMsg::Hook(event) => {
+                    self.manage_hook_event(event);
+                    self.advance_rollouts();
+                }
             }
         }
     }
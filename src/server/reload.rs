@@ -0,0 +1,180 @@
+use logger::{LogLevel, Logger};
+use std::{
+    net::TcpListener,
+    sync::mpsc::{self, Sender},
+};
+use taskmeister::ResponsePart;
+
+use crate::{
+    config::Config,
+    orchestrate::{OrchestratorMsg, OrchestratorRequest, OrchestratorSender},
+    service::ServiceAction,
+};
+
+/// Applies a new server.toml to the running server, the jobs keep running.
+/// What can only be set on start is reported instead
+pub struct ServerReload {
+    config: Config,   // The one in effect
+    addr_fixed: bool, // Given on the command line, the file does not change it
+    requests: OrchestratorSender,
+    listener: Sender<TcpListener>, // The main loop accepts on the last one sent
+    logger: Logger,
+}
+
+impl ServerReload {
+    pub fn new(
+        config: Config,
+        addr_fixed: bool,
+        requests: OrchestratorSender,
+        listener: Sender<TcpListener>,
+        logger: Logger,
+    ) -> ServerReload {
+        ServerReload {
+            config,
+            addr_fixed,
+            requests,
+            listener,
+            logger,
+        }
+    }
+
+    /// Re-reads server.toml and the services. Returns the parts of the change
+    /// that could not be applied
+    pub fn reload(&mut self) -> Vec<String> {
+        let mut not_applied = Vec::new();
+
+        let mut new = match self.config.reload() {
+            Ok(new) => new,
+            Err(err) => {
+                // The services are still reloaded with the current config
+                not_applied.push(format!("server config: {err}"));
+                if let Err(err) = self.request(ServiceAction::Reload(false)) {
+                    not_applied.push(format!("services: {err}"));
+                }
+                return not_applied;
+            }
+        };
+        let old = &self.config;
+
+        if (&new.log_level, &new.logs, new.syslog) != (&old.log_level, &old.logs, old.syslog)
+            && let Err(err) =
+                self.logger
+                    .reconfigure(new.log_level.clone(), new.logs.clone(), new.syslog)
+        {
+            not_applied.push(format!("logs: {err}"));
+            new.log_level = old.log_level.clone();
+            new.logs = old.logs.clone();
+            new.syslog = old.syslog;
+        }
+
+        if new.service_sources() != old.service_sources() {
+            // An info part tells the sources were loaded, the errors are then
+            // of jobs that failed to start or stop
+            let parts = self
+                .responses(ServiceAction::Sources(Box::new(new.service_sources())))
+                .unwrap_or_else(|err| vec![ResponsePart::Error(err)]);
+            let loaded = parts
+                .iter()
+                .any(|part| matches!(part, ResponsePart::Info(_)));
+
+            for part in parts {
+                if let ResponsePart::Error(err) = part {
+                    match loaded {
+                        true => not_applied.push(format!("services: {err}")),
+                        false => {
+                            not_applied.push(format!("include, defaults and templates: {err}"))
+                        }
+                    }
+                }
+            }
+            if !loaded {
+                new.include = old.include.clone();
+                new.defaults = old.defaults.clone();
+                new.templates = old.templates.clone();
+            }
+        } else if let Err(err) = self.request(ServiceAction::Reload(false)) {
+            not_applied.push(format!("services: {err}"));
+        }
+
+        // Only the services just listed are started, the others were already
+        for alias in &new.start.services {
+            if !old.start.services.contains(alias)
+                && let Err(err) = self.request(ServiceAction::Start(alias.clone()))
+            {
+                not_applied.push(format!("start.services: {alias}: {err}"));
+            }
+        }
+
+        if new.server_addr != old.server_addr {
+            let rebind = match self.addr_fixed {
+                true => Err("set on the command line".to_string()),
+                false => TcpListener::bind(new.server_addr)
+                    .map_err(|err| err.to_string())
+                    .and_then(|listener| {
+                        self.listener
+                            .send(listener)
+                            .map_err(|_| "the main loop is gone".to_string())
+                    }),
+            };
+            match rebind {
+                Ok(_) => logger::info!(self.logger, "Listening on {}", new.server_addr),
+                Err(err) => {
+                    not_applied.push(format!("server_addr: {err}"));
+                    new.server_addr = old.server_addr;
+                }
+            }
+        }
+
+        // Used only when the server starts
+        if new.backpressure != old.backpressure {
+            not_applied.push("backpressure: needs a server restart".to_string());
+            new.backpressure = old.backpressure.clone();
+        }
+        if new.journal != old.journal {
+            not_applied.push("journal: needs a server restart".to_string());
+            new.journal = old.journal.clone();
+        }
+        if new.history != old.history {
+            not_applied.push("history: needs a server restart".to_string());
+            new.history = old.history.clone();
+        }
+        if new.metrics != old.metrics {
+            not_applied.push("metrics: needs a server restart".to_string());
+            new.metrics = old.metrics;
+        }
+        if new.watch_config != old.watch_config {
+            not_applied.push("watch_config: needs a server restart".to_string());
+            new.watch_config = old.watch_config;
+        }
+
+        self.config = new;
+        not_applied
+    }
+
+    fn request(&self, action: ServiceAction) -> Result<(), String> {
+        match self
+            .responses(action)?
+            .into_iter()
+            .find_map(|part| match part {
+                ResponsePart::Error(err) => Some(err),
+                _ => None,
+            }) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    // Every part of the answer, once the orchestrator is done with it
+    fn responses(&self, action: ServiceAction) -> Result<Vec<ResponsePart>, String> {
+        let (tx, rx) = mpsc::channel();
+
+        self.requests
+            .send(OrchestratorMsg::Request(OrchestratorRequest {
+                action,
+                response_channel: tx,
+            }))
+            .map_err(|err| err.to_string())?;
+
+        Ok(rx.into_iter().collect())
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    fs, io,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+// Seconds between refreshes of top unless asked otherwise
+pub const TOP_INTERVAL: u64 = 2;
+
+/// Resources in use by a running process, sampled from /proc
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProcUsage {
+    pub cpu_percent: f64, // Since the previous sample, 100 is one full core
+    pub rss: u64,         // In bytes
+    pub fds: usize,
+    pub threads: usize,
+    pub uptime: Duration,
+}
+
+impl std::fmt::Display for ProcUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "CPU: {:.1}% RSS: {} FDs: {} Threads: {} Uptime: {}",
+            self.cpu_percent,
+            format_bytes(self.rss),
+            self.fds,
+            self.threads,
+            format_duration(self.uptime)
+        )
+    }
+}
+
+// Keeps what is needed between samples to compute the cpu percent
+#[derive(Debug)]
+pub struct Sampler {
+    started_at: Instant,
+    previous: Option<(u64, Instant)>, // Cpu ticks and when they were read
+    pub usage: Option<ProcUsage>,
+}
+
+impl Sampler {
+    pub fn new() -> Sampler {
+        Sampler {
+            started_at: Instant::now(),
+            previous: None,
+            usage: None,
+        }
+    }
+
+    pub fn sample(&mut self, pid: u32) -> Result<&ProcUsage, io::Error> {
+        let proc_dir = format!("/proc/{pid}");
+        let now = Instant::now();
+
+        // The command name may contain spaces, fields are counted after it
+        let stat = fs::read_to_string(format!("{proc_dir}/stat"))?;
+        let fields: Vec<&str> = stat
+            .rsplit_once(')')
+            .map(|(_, fields)| fields.split_whitespace().collect())
+            .unwrap_or_default();
+        let ticks = match (stat_field(&fields, 14), stat_field(&fields, 15)) {
+            (Some(utime), Some(stime)) => utime + stime,
+            _ => return Err(io::Error::other(format!("Malformed {proc_dir}/stat"))),
+        };
+
+        let cpu_percent = match self.previous {
+            Some((previous_ticks, previous_time)) => {
+                let elapsed = now.duration_since(previous_time).as_secs_f64();
+                let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+
+                if elapsed > 0.0 && ticks_per_sec > 0.0 {
+                    ticks.saturating_sub(previous_ticks) as f64 / ticks_per_sec / elapsed * 100.0
+                } else {
+                    0.0
+                }
+            }
+            None => 0.0,
+        };
+        self.previous = Some((ticks, now));
+
+        let resident_pages = fs::read_to_string(format!("{proc_dir}/statm"))?
+            .split_whitespace()
+            .nth(1)
+            .and_then(|pages| pages.parse::<u64>().ok())
+            .unwrap_or_default();
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+
+        Ok(self.usage.insert(ProcUsage {
+            cpu_percent,
+            rss: resident_pages * page_size,
+            fds: fs::read_dir(format!("{proc_dir}/fd"))?.count(),
+            threads: fs::read_dir(format!("{proc_dir}/task"))?.count(),
+            uptime: self.started_at.elapsed(),
+        }))
+    }
+}
+
+/// Column used to sort the top table
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum TopSort {
+    #[default]
+    Cpu,
+    Rss,
+    Fds,
+    Threads,
+    Uptime,
+    Alias,
+}
+
+impl FromStr for TopSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cpu" => Ok(TopSort::Cpu),
+            "rss" | "mem" => Ok(TopSort::Rss),
+            "fds" => Ok(TopSort::Fds),
+            "threads" => Ok(TopSort::Threads),
+            "uptime" => Ok(TopSort::Uptime),
+            "alias" => Ok(TopSort::Alias),
+            other => Err(format!("Unknown column: {other}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TopOptions {
+    pub sort: TopSort,
+    pub interval: Option<u64>, // In seconds
+    pub count: Option<u64>,    // Refreshes before ending, forever if not set
+}
+
+/// Renders the rows (alias, pid, usage) sorted as requested, highest first
+pub fn top_table(mut rows: Vec<(String, u32, ProcUsage)>, sort: &TopSort) -> String {
+    rows.sort_by(|(alias1, _, usage1), (alias2, _, usage2)| match sort {
+        TopSort::Cpu => usage2.cpu_percent.total_cmp(&usage1.cpu_percent),
+        TopSort::Rss => usage2.rss.cmp(&usage1.rss),
+        TopSort::Fds => usage2.fds.cmp(&usage1.fds),
+        TopSort::Threads => usage2.threads.cmp(&usage1.threads),
+        TopSort::Uptime => usage2.uptime.cmp(&usage1.uptime),
+        TopSort::Alias => alias1.cmp(alias2),
+    });
+
+    rows.iter().fold(
+        format!(
+            "{:<20} {:>8} {:>7} {:>10} {:>6} {:>8} {:>12}\n",
+            "ALIAS", "PID", "CPU%", "RSS", "FDS", "THREADS", "UPTIME"
+        ),
+        |acc, (alias, pid, usage)| {
+            acc + &format!(
+                "{:<20} {:>8} {:>7.1} {:>10} {:>6} {:>8} {:>12}\n",
+                alias,
+                pid,
+                usage.cpu_percent,
+                format_bytes(usage.rss),
+                usage.fds,
+                usage.threads,
+                format_duration(usage.uptime)
+            )
+        },
+    )
+}
+
+// Field of /proc/<pid>/stat by its number in proc(5), the slice starts at
+// field 3, right after the command name
+fn stat_field(fields: &[&str], number: usize) -> Option<u64> {
+    fields.get(number - 3).and_then(|field| field.parse().ok())
+}
+
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 30 => format!("{:.1}GiB", b as f64 / (1u64 << 30) as f64),
+        b if b >= 1 << 20 => format!("{:.1}MiB", b as f64 / (1u64 << 20) as f64),
+        b => format!("{:.1}KiB", b as f64 / 1024.0),
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        s if s >= 86400 => format!("{}d{}h", s / 86400, s % 86400 / 3600),
+        s if s >= 3600 => format!("{}h{}m", s / 3600, s % 3600 / 60),
+        s if s >= 60 => format!("{}m{}s", s / 60, s % 60),
+        s => format!("{s}s"),
+    }
+}
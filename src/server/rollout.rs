@@ -0,0 +1,267 @@
+use logger::LogLevel;
+use std::{
+    collections::VecDeque,
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
+use taskmeister::ResponsePart;
+
+use crate::{
+    hooks::{HOOK_TIMEOUT, HookPoint},
+    jobs::JobStatus,
+    orchestrate::{Orchestrator, OrchestratorError},
+    service::Service,
+};
+
+// Slack over what a job of the batch should take to be healthy again
+const ROLLOUT_GRACE: Duration = Duration::from_secs(5);
+
+/// Restart of the jobs of a service a batch at a time, the next batch starts
+/// once every job of the previous one is healthy again
+pub struct Rollout {
+    alias: String, // Of the service
+    pending: VecDeque<String>,
+    batch: Vec<(String, u64)>, // Jobs restarting and their restarts before it
+    batch_size: usize,
+    restarted: usize,
+    timeout: Duration, // For the batch to be healthy, it is aborted after it
+    deadline: Instant,
+    response: Option<Sender<ResponsePart>>, // Of the client, none on reload
+}
+
+// Where a job of the batch is
+enum Progress {
+    Restarting,
+    Healthy,
+    Failed(String),
+}
+
+impl Orchestrator {
+    /// Restarts the running jobs of a service batch_size at a time
+    pub fn rolling_restart(
+        &mut self,
+        alias: &str,
+        batch_size: usize,
+        response: Option<Sender<ResponsePart>>,
+    ) -> Result<(), OrchestratorError> {
+        let service = self
+            .get_services()
+            .get(alias)
+            .ok_or(OrchestratorError::ServiceNotFound)?;
+        let jobs = taskmeister::generate_alias_names(alias, service.numprocs).collect();
+
+        self.start_rollout(alias, jobs, batch_size, response)
+    }
+
+    /// Same for some jobs of the service, the ones not running are skipped
+    /// since they get the changes on their next start
+    pub fn start_rollout(
+        &mut self,
+        alias: &str,
+        jobs: Vec<String>,
+        batch_size: usize,
+        response: Option<Sender<ResponsePart>>,
+    ) -> Result<(), OrchestratorError> {
+        if self.rollouts.iter().any(|rollout| rollout.alias == alias) {
+            return Err(OrchestratorError::RolloutInProgress);
+        }
+
+        let pending: VecDeque<String> = jobs
+            .into_iter()
+            .filter(|job| {
+                matches!(
+                    self.get_job_status(job),
+                    Some(JobStatus::Starting | JobStatus::Running(_) | JobStatus::Paused)
+                )
+            })
+            .collect();
+        if pending.is_empty() {
+            return Err(OrchestratorError::ServiceStopped);
+        }
+
+        logger::info!(
+            self.logger,
+            "[Rollout] [{alias}] Restarting {} jobs, {} at a time",
+            pending.len(),
+            batch_size
+        );
+        self.rollouts.push(Rollout {
+            alias: alias.to_string(),
+            pending,
+            batch: Vec::new(),
+            batch_size: batch_size.max(1),
+            restarted: 0,
+            timeout: Duration::ZERO,
+            deadline: Instant::now(),
+            response,
+        });
+        self.advance_rollouts();
+
+        Ok(())
+    }
+
+    /// Adds jobs to the rollout of the service in progress, false when there
+    /// is none. Jobs already restarted go again, they may run the replaced
+    /// config
+    pub fn extend_rollout(&mut self, alias: &str, jobs: &[String]) -> bool {
+        let Some(rollout) = self
+            .rollouts
+            .iter_mut()
+            .find(|rollout| rollout.alias == alias)
+        else {
+            return false;
+        };
+
+        for job in jobs {
+            if !rollout.pending.contains(job) {
+                rollout.pending.push_back(job.clone());
+            }
+        }
+        logger::info!(
+            self.logger,
+            "[Rollout] [{alias}] {} jobs pending after a reload",
+            rollout.pending.len()
+        );
+
+        true
+    }
+
+    /// Moves every rollout on with the status of its jobs, called after each
+    /// job event
+    pub fn advance_rollouts(&mut self) {
+        for mut rollout in std::mem::take(&mut self.rollouts) {
+            if self.advance_rollout(&mut rollout) {
+                self.rollouts.push(rollout);
+            }
+        }
+    }
+
+    // False once the rollout is over, done or aborted
+    fn advance_rollout(&mut self, rollout: &mut Rollout) -> bool {
+        let mut restarting = Vec::new();
+        for (job, restarts) in &rollout.batch {
+            match self.rollout_progress(job, *restarts) {
+                Progress::Restarting => restarting.push(job.as_str()),
+                Progress::Healthy => (),
+                Progress::Failed(reason) => {
+                    let message = format!(
+                        "{job} did not become healthy ({reason}), {} jobs not restarted",
+                        rollout.pending.len()
+                    );
+                    logger::error!(self.logger, "[Rollout] [{}] {message}", rollout.alias);
+                    rollout.report(ResponsePart::Error(message));
+                    return false;
+                }
+            }
+        }
+        if !restarting.is_empty() {
+            if Instant::now() < rollout.deadline {
+                return true;
+            }
+
+            let message = format!(
+                "{} not healthy after {}s, {} jobs not restarted",
+                restarting.join(", "),
+                rollout.timeout.as_secs(),
+                rollout.pending.len()
+            );
+            logger::error!(self.logger, "[Rollout] [{}] {message}", rollout.alias);
+            rollout.report(ResponsePart::Error(message));
+            return false;
+        }
+        rollout.restarted += rollout.batch.len();
+        rollout.batch.clear();
+        rollout.timeout = Duration::ZERO;
+
+        while rollout.batch.len() < rollout.batch_size
+            && let Some(job) = rollout.pending.pop_front()
+        {
+            self.reset_job_retries(&job);
+            let restarts = self.jobs.get(&job).map_or(0, |job| job.restarts);
+
+            match self.stop_request(&job, false, true) {
+                Ok(_) => {
+                    logger::info!(
+                        self.logger,
+                        "[Rollout] [{}] Restarting {job}",
+                        rollout.alias
+                    );
+                    if let Some(service) = self.get_services().get(&job) {
+                        rollout.timeout = rollout.timeout.max(restart_time(service));
+                    }
+                    rollout.batch.push((job, restarts));
+                }
+                // Stopped since the rollout started
+                Err(OrchestratorError::ServiceStopped) | Err(OrchestratorError::JobNotFound) => (),
+                Err(err) => {
+                    let message = format!(
+                        "Restarting {job}: {err}, {} jobs not restarted",
+                        rollout.pending.len()
+                    );
+                    logger::error!(self.logger, "[Rollout] [{}] {message}", rollout.alias);
+                    rollout.report(ResponsePart::Error(message));
+                    return false;
+                }
+            }
+        }
+
+        rollout.deadline = Instant::now() + rollout.timeout;
+
+        if rollout.batch.is_empty() {
+            let message = format!("{}: {} jobs restarted", rollout.alias, rollout.restarted);
+            logger::info!(self.logger, "[Rollout] [{}] Done", rollout.alias);
+            rollout.report(ResponsePart::Info(message));
+            return false;
+        }
+
+        true
+    }
+
+    // A job that exits or is started again by its restart policy is failed
+    fn rollout_progress(&self, alias: &str, restarts: u64) -> Progress {
+        let Some(job) = self.jobs.get(alias) else {
+            // Removed by a reload meanwhile
+            return Progress::Healthy;
+        };
+
+        // Stopping or starting once its hook is done
+        if job.hook.is_some() {
+            return Progress::Restarting;
+        }
+        if job.restarts > restarts + 1 {
+            return Progress::Failed("restarted again after exiting".to_string());
+        }
+        match &job.status {
+            JobStatus::Running(true) => Progress::Healthy,
+            JobStatus::Finished(reason) => Progress::Failed(reason.to_string()),
+            JobStatus::Created => Progress::Failed("not started".to_string()),
+            _ => Progress::Restarting,
+        }
+    }
+}
+
+// What a job may take to stop and be healthy again: the whole stop sequence,
+// the hooks around it and the start time
+fn restart_time(service: &Service) -> Duration {
+    let stop: u64 = service
+        .stop_steps()
+        .iter()
+        .map(|step| step.wait)
+        .sum::<u64>()
+        + service.stop_wait;
+    let hooks: u64 = [HookPoint::PreStop, HookPoint::PreStart]
+        .into_iter()
+        .filter_map(|point| point.hook(service))
+        .map(|hook| hook.timeout.unwrap_or(HOOK_TIMEOUT))
+        .sum();
+
+    Duration::from_secs(stop + hooks + service.start_time) + ROLLOUT_GRACE
+}
+
+impl Rollout {
+    fn report(&self, part: ResponsePart) {
+        if let Some(response) = &self.response {
+            response.send(part).ok();
+        }
+    }
+}
@@ -1,10 +1,18 @@
-use serde::{Deserialize, Deserializer, Serialize, de};
+use crate::{
+    check::{self, Problem},
+    jobs::ExitReason,
+    journal::SearchQuery,
+    resources::TopOptions,
+};
+use logger::LogLevel;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
 use std::{
     collections::{HashMap, hash_map::Entry},
+    ffi::OsStr,
     fs::{self},
     io,
-    os::unix::process::CommandExt,
-    path::PathBuf,
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt, process::CommandExt},
+    path::{Path, PathBuf},
     process::{Child, Command, Stdio},
 };
 use taskmeister::dir_utils;
@@ -14,12 +22,26 @@ use taskmeister::dir_utils;
 pub enum ServiceAction {
     Start(String),
     Restart(String),
+    RollingRestart(String, usize), // Jobs at a time
     Stop(String),
-    Status(String),
+    Status(String, bool), // With the effective configuration instead
     Attach(String),
     Detach(String),
     Input(String, Vec<u8>),
-    Reload,
+    Signal(String, i32),
+    Pause(String),
+    Resume(String),
+    Reopen(String),
+    Search(String, Box<SearchQuery>),
+    History(String),
+    Top(TopOptions),
+    Events(Option<String>), // Alias filter
+    Metrics,
+    Reload(bool), // Dry run
+    CheckConfig,
+    Sources(Box<ServiceSources>),
+    ReloadJob(String),
+    Scale(String, u16, bool), // Persist in the service file
     List,
     Help,
 }
@@ -33,73 +55,679 @@ pub enum RestartOptions {
     Unexpected(u8),
 }
 
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Clone)]
+pub enum OutputMode {
+    #[default]
+    Append,
+    Truncate,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub enum TimestampFormat {
+    Logger,  // Same format as the server logs
+    Rfc3339, // With milliseconds
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Clone)]
+pub enum SyslogFacility {
+    #[default]
+    User,
+    Daemon,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl SyslogFacility {
+    pub fn posix_facility(&self) -> libc::c_int {
+        match self {
+            SyslogFacility::User => libc::LOG_USER,
+            SyslogFacility::Daemon => libc::LOG_DAEMON,
+            SyslogFacility::Local0 => libc::LOG_LOCAL0,
+            SyslogFacility::Local1 => libc::LOG_LOCAL1,
+            SyslogFacility::Local2 => libc::LOG_LOCAL2,
+            SyslogFacility::Local3 => libc::LOG_LOCAL3,
+            SyslogFacility::Local4 => libc::LOG_LOCAL4,
+            SyslogFacility::Local5 => libc::LOG_LOCAL5,
+            SyslogFacility::Local6 => libc::LOG_LOCAL6,
+            SyslogFacility::Local7 => libc::LOG_LOCAL7,
+        }
+    }
+}
+
+/// How the stdout and stderr of a service are written
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Clone)]
+#[serde(default)]
+pub struct OutputOptions {
+    pub mode: OutputMode,
+    pub max_size: u64, // In bytes, 0 means the file is never rotated by size
+    pub backups: u16,  // Rotated files kept as <file>.1, <file>.2, ...
+    pub rotate_on_restart: bool,
+    // Prefixes for each captured line, attached clients still get raw bytes
+    pub timestamp: Option<TimestampFormat>,
+    pub prefix_alias: bool,
+    pub prefix_stream: bool,
+    // Used when the output goes to "logger" or "syslog"
+    pub log_level: LogLevel,
+    pub syslog_facility: SyslogFacility,
+}
+
+/// Resource limits of each process of a service, a process over any of them
+/// is restarted
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(default)]
+pub struct Watchdog {
+    pub max_rss: Option<u64>, // In bytes
+    pub max_cpu: Option<f64>, // Percent, 100 is one full core
+    pub cpu_window: u64,      // Seconds the cpu must stay over max_cpu
+    pub max_fds: Option<usize>,
+}
+
+// A short spike over max_cpu is not a reason to restart
+impl Default for Watchdog {
+    fn default() -> Self {
+        Watchdog {
+            max_rss: None,
+            max_cpu: None,
+            cpu_window: default_cpu_window(),
+            max_fds: None,
+        }
+    }
+}
+
+/// Command run at some point of the life of a job. It gets the environment of
+/// the service plus TASKMEISTER_* variables describing the event
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Hook {
+    pub cmd: String,
+    pub timeout: Option<u64>, // In seconds, killed after it
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Clone)]
+#[serde(default)]
+pub struct Hooks {
+    pub pre_start: Option<Hook>,  // A failure aborts the start
+    pub post_start: Option<Hook>, // Once the job is healthy
+    pub pre_stop: Option<Hook>,
+    pub post_stop: Option<Hook>,
+    pub on_failure: Option<Hook>, // Unexpected exit that will not be retried
+}
+
+/// What a step of the stop sequence does to the job
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(untagged)]
+pub enum StopAction {
+    Signal {
+        #[serde(
+            serialize_with = "serialize_signal",
+            deserialize_with = "deserialize_signal"
+        )]
+        signal: i32,
+    },
+    Cmd {
+        cmd: String,
+    },
+}
+
+/// Step of the stop sequence, the next one runs if the job is still alive
+/// after the wait, e.g. { signal = "INT", wait = 10 } or
+/// { cmd = "app ctl shutdown", wait = 10 }
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct StopStep {
+    #[serde(flatten)]
+    pub action: StopAction,
+    pub wait: u64, // In seconds
+}
+
+/// What a config reload does to a service, with the fields that decided it
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServiceChange {
+    Added(bool), // Autostart
+    Removed,
+    Restart(Vec<&'static str>), // A field used to spawn the process changed
+    Reload(Vec<&'static str>),  // Applied live and the job reloads in place
+    Update(Vec<&'static str>),  // Applied live
+    Unchanged,
+}
+
+impl std::fmt::Display for ServiceChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServiceChange::Added(true) => write!(f, "start (new service)"),
+            ServiceChange::Added(false) => write!(f, "add (new service, no autostart)"),
+            ServiceChange::Removed => write!(f, "stop (removed)"),
+            ServiceChange::Restart(fields) => write!(f, "restart ({})", fields.join(", ")),
+            ServiceChange::Reload(fields) => write!(f, "reload ({})", fields.join(", ")),
+            ServiceChange::Update(fields) => write!(f, "update ({})", fields.join(", ")),
+            ServiceChange::Unchanged => write!(f, "unchanged"),
+        }
+    }
+}
+
+// Fields used to spawn the process, when any changes a new process is needed
+const SPAWN_FIELDS: [&str; 11] = [
+    "cmd",
+    "env",
+    "env_files",
+    "clear_env",
+    "inherit_env",
+    "working_dir",
+    "umask",
+    "stdin",
+    "stdout",
+    "stderr",
+    "output",
+];
+
+/// Entry of exit_codes: either an exit code or the name of a signal that
+/// terminated the job, e.g. exit_codes = [0, 2, "TERM"]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(untagged)]
+pub enum ExpectedExit {
+    Code(i32),
+    #[serde(
+        serialize_with = "serialize_signal",
+        deserialize_with = "deserialize_signal"
+    )]
+    Signal(i32),
+}
+
+// Only alias and cmd are required, see the defaults below
 #[derive(Debug, Serialize, Deserialize, Default, PartialEq, Clone)]
 pub struct Service {
     #[serde(skip)]
     pub file: PathBuf,
+    #[serde(skip)]
+    pub dropins: Vec<PathBuf>, // From <alias>.d, applied over the file
+    #[serde(skip)]
+    pub instance: usize, // Of the numprocs jobs, 0 is the one named alias
     pub alias: String,
     cmd: String,
+    #[serde(default = "default_numprocs")]
     pub numprocs: u16,
+    #[serde(default)]
     pub restart: RestartOptions,
+    #[serde(default = "default_start_time")]
     pub start_time: u64,
-    #[serde(deserialize_with = "deserialize_signal")]
+    #[serde(
+        default = "default_stop_signal",
+        serialize_with = "serialize_signal",
+        deserialize_with = "deserialize_signal"
+    )]
     pub stop_signal: i32,
+    #[serde(default = "default_stop_wait")]
     pub stop_wait: u64,
-    exit_codes: Vec<i32>,
+    // Replaces stop_signal, after the last step the job is killed
+    #[serde(default)]
+    stop_sequence: Vec<StopStep>,
+    // How the job reloads in place, a signal (e.g. "HUP") or a command
+    #[serde(
+        default,
+        serialize_with = "serialize_option_signal",
+        deserialize_with = "deserialize_option_signal"
+    )]
+    pub reload_signal: Option<i32>,
+    pub reload_cmd: Option<String>,
+    // Started when added by a config reload
+    #[serde(default)]
+    pub autostart: bool,
+    #[serde(default = "default_exit_codes")]
+    exit_codes: Vec<ExpectedExit>,
+    // File path, "null", "logger" (server logger) or "syslog"
+    #[serde(default = "default_stdio")]
     pub stdout: String,
+    #[serde(default = "default_stdio")]
     pub stdin: String,
+    #[serde(default = "default_stdio")]
     pub stderr: String,
+    #[serde(default)]
+    pub output: OutputOptions,
+    #[serde(default)]
+    pub watchdog: Watchdog,
+    #[serde(default)]
+    pub hooks: Hooks,
+    // Values may use ${VAR}, as cmd, working_dir, stdout and stderr
+    #[serde(default)]
     env: HashMap<String, String>,
+    // Dotenv files read before env, relative to the service file
+    #[serde(default)]
+    env_files: Vec<PathBuf>,
+    // Start from an empty environment, only inherit_env is taken from the
+    // server. A non empty inherit_env also clears the rest
+    #[serde(default)]
+    clear_env: bool,
+    #[serde(default)]
+    inherit_env: Vec<String>,
+    #[serde(default = "default_working_dir")]
     working_dir: PathBuf,
+    #[serde(default = "default_umask")]
     umask: u32,
 }
 
+fn default_numprocs() -> u16 {
+    1
+}
+
+fn default_start_time() -> u64 {
+    1
+}
+
+fn default_stop_signal() -> i32 {
+    libc::SIGTERM
+}
+
+fn default_stop_wait() -> u64 {
+    10
+}
+
+fn default_cpu_window() -> u64 {
+    10
+}
+
+fn default_exit_codes() -> Vec<ExpectedExit> {
+    vec![ExpectedExit::Code(0)]
+}
+
+fn default_stdio() -> String {
+    "null".to_string()
+}
+
+// Same directory as the server
+fn default_working_dir() -> PathBuf {
+    PathBuf::from(".")
+}
+
+fn default_umask() -> u32 {
+    0o022
+}
+
+/// Where the services come from: the include paths of server.toml, its
+/// [defaults] and the [templates.<name>] that service files extend
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServiceSources {
+    pub paths: Vec<PathBuf>,
+    pub patterns: Vec<String>, // Globs of the file names read in the paths
+    pub defaults: toml::Table,
+    pub templates: HashMap<String, toml::Table>,
+}
+
+impl ServiceSources {
+    // The defaults, then the templates from the farthest one, then the file
+    fn merge(&self, mut table: toml::Table) -> Result<toml::Table, String> {
+        let mut chain: Vec<(String, toml::Table)> = Vec::new();
+        let mut extends = table.remove("extends");
+
+        while let Some(name) = extends {
+            let toml::Value::String(name) = name else {
+                return Err("extends must be the name of a template".to_string());
+            };
+            if chain.iter().any(|(extended, _)| *extended == name) {
+                return Err(format!("Template {name} extends itself"));
+            }
+            let mut template = self
+                .templates
+                .get(&name)
+                .cloned()
+                .ok_or(format!("Unknown template: {name}"))?;
+
+            extends = template.remove("extends");
+            chain.push((name, template));
+        }
+
+        let mut merged = self.defaults.clone();
+        for (_, template) in chain.into_iter().rev() {
+            merge_tables(&mut merged, template);
+        }
+        merge_tables(&mut merged, table);
+
+        Ok(merged)
+    }
+}
+
+// Tables are merged key by key, anything else is replaced
+fn merge_tables(base: &mut toml::Table, over: toml::Table) {
+    for (key, value) in over {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(over)) => merge_tables(base, over),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// A service file merged over its templates and the defaults. Problems are
+/// placed in the file when possible
+pub fn parse_services(
+    file: &Path,
+    text: &str,
+    sources: &ServiceSources,
+) -> Result<Vec<Service>, Problem> {
+    let mut table: toml::Table =
+        toml::from_str(text).map_err(|err| Problem::from_toml(file, text, &err))?;
+
+    // A [[service]] array defines many services, the rest of the file is
+    // shared by all of them
+    let entries = match table.remove("service") {
+        Some(toml::Value::Array(entries)) => entries
+            .into_iter()
+            .map(|entry| match entry {
+                toml::Value::Table(entry) => {
+                    let mut shared = table.clone();
+                    merge_tables(&mut shared, entry);
+                    Ok(shared)
+                }
+                _ => Err(Problem::at_key(
+                    file,
+                    text,
+                    "service",
+                    "service must be an array of tables, [[service]]".to_string(),
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        Some(_) => {
+            return Err(Problem::at_key(
+                file,
+                text,
+                "service",
+                "service must be an array of tables, [[service]]".to_string(),
+            ));
+        }
+        None => vec![table],
+    };
+
+    entries
+        .into_iter()
+        .map(|mut entry| {
+            let dropins = apply_dropins(file, &mut entry, &sources.patterns)?;
+            let merged = sources
+                .merge(entry)
+                .map_err(|message| Problem::at_key(file, text, "extends", message))?;
+
+            let mut service = Service::deserialize(toml::Value::Table(merged))
+                .map_err(|err| Problem::from_value(file, text, &err))?;
+            service.file = file.to_path_buf();
+            service.dropins = dropins;
+
+            Ok(service)
+        })
+        .collect()
+}
+
+// Files of the <alias>.d directory next to the definition, merged over it in
+// name order. Returns the ones applied
+fn apply_dropins(
+    file: &Path,
+    entry: &mut toml::Table,
+    patterns: &[String],
+) -> Result<Vec<PathBuf>, Problem> {
+    let Some(toml::Value::String(alias)) = entry.get("alias") else {
+        return Ok(Vec::new());
+    };
+    let dir = file
+        .parent()
+        .unwrap_or(Path::new("."))
+        .join(format!("{alias}.d"));
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let dropins = service_files(&dir, patterns).map_err(|err| Problem {
+        file: dir.clone(),
+        position: None,
+        message: err.to_string(),
+    })?;
+
+    for dropin in &dropins {
+        let text = fs::read_to_string(dropin).map_err(|err| Problem {
+            file: dropin.clone(),
+            position: None,
+            message: err.to_string(),
+        })?;
+        let table: toml::Table =
+            toml::from_str(&text).map_err(|err| Problem::from_toml(dropin, &text, &err))?;
+        merge_tables(entry, table);
+    }
+
+    Ok(dropins)
+}
+
+/// Service files under the path, sorted so the load order does not change.
+/// A directory named <alias>.d next to the file defining alias holds drop-ins,
+/// they are read with their service. Other directories are searched
+pub fn service_files(path: &Path, patterns: &[String]) -> Result<Vec<PathBuf>, io::Error> {
+    // A file given in the include paths is always read
+    if path.is_file() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut entries = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+
+    let matches = |entry: &Path| {
+        entry.is_file()
+            && entry.file_name().is_some_and(|name| {
+                patterns
+                    .iter()
+                    .any(|pattern| dir_utils::glob_match(pattern, &name.to_string_lossy()))
+            })
+    };
+    let aliases: Vec<String> = entries
+        .iter()
+        .filter(|entry| matches(entry))
+        .flat_map(|entry| file_aliases(entry))
+        .collect();
+
+    let mut files = Vec::new();
+    for entry in entries {
+        if entry.is_dir() {
+            let dropins = entry.extension() == Some(OsStr::new("d"))
+                && entry
+                    .file_stem()
+                    .is_some_and(|stem| aliases.iter().any(|alias| stem == alias.as_str()));
+            if !dropins {
+                files.extend(service_files(&entry, patterns)?);
+            }
+        } else if matches(&entry) {
+            files.push(entry);
+        }
+    }
+
+    Ok(files)
+}
+
+// Aliases set in a service file, of the service or of its [[service]] entries.
+// A file that does not parse has none, the error comes when it is loaded
+fn file_aliases(file: &Path) -> Vec<String> {
+    let Some(table) = fs::read_to_string(file)
+        .ok()
+        .and_then(|text| text.parse::<toml::Table>().ok())
+    else {
+        return Vec::new();
+    };
+    let alias = |table: &toml::Table| match table.get("alias") {
+        Some(toml::Value::String(alias)) => Some(alias.clone()),
+        _ => None,
+    };
+
+    match table.get("service") {
+        Some(toml::Value::Array(entries)) => entries
+            .iter()
+            .filter_map(|entry| entry.as_table().and_then(alias))
+            .chain(alias(&table))
+            .collect(),
+        _ => alias(&table).into_iter().collect(),
+    }
+}
+
 // Cannot implement methods of foreign types, use struct wrapper to abstract it
 #[derive(Debug)]
 pub struct Services {
-    paths: Vec<PathBuf>,
+    sources: ServiceSources,
     services: HashMap<String, Service>,
+    // Set with scale, by alias: the numprocs of the file then and the new one
+    scaled: HashMap<String, (u16, u16)>,
 }
 
 impl Services {
-    pub fn new(paths: Vec<PathBuf>) -> Result<Self, io::Error> {
-        let services = load_services(&paths)?;
+    pub fn new(sources: ServiceSources) -> Result<Self, io::Error> {
+        let scaled = HashMap::new();
+        let services = load_services(&sources, &scaled)?;
 
-        Ok(Services { paths, services })
+        Ok(Services {
+            sources,
+            services,
+            scaled,
+        })
     }
 
     /// Update current Services with the new structure: new becomes the new services
-    /// and a diff is returned with the services that changed
-    pub fn update(&mut self) -> Result<Vec<ServiceAction>, io::Error> {
-        let mut up = vec![];
-        let mut new_services = load_services(&self.paths)?;
+    /// and the change of every alias is returned
+    pub fn update(&mut self) -> Result<Vec<(String, ServiceChange)>, io::Error> {
+        let mut new_services = load_services(&self.sources, &self.scaled)?;
+        let changes = self.diff(&new_services);
 
-        for (alias, serv) in &new_services {
-            match self.services.entry(alias.clone()) {
-                Entry::Occupied(o) => {
-                    // If self contains the entry, check if it changed
-                    if *o.get() != *serv {
-                        up.push(ServiceAction::Restart(alias.clone()));
-                    }
-                    self.services.remove(alias);
-                }
-                Entry::Vacant(_) => (),
-            };
-        }
+        // A numprocs changed in the file wins over the scaled one
+        self.scaled.retain(|alias, (_, numprocs)| {
+            new_services
+                .get(alias)
+                .is_some_and(|service| service.numprocs == *numprocs)
+        });
 
-        // Remaining in self are the services to stop. This means this service
-        // has disapeared, so also means to remove the service
-        up.extend(
-            self.services
-                .iter()
-                .map(|(alias, _)| ServiceAction::Stop(alias.clone())),
-        );
-
-        // Update self with the new entries
+        // Removed services are kept until their job is stopped, they are
+        // removed from the orchestrator
+        self.services
+            .retain(|alias, _| !new_services.contains_key(alias));
         new_services.extend(self.services.drain());
         self.services = new_services;
 
-        Ok(up)
+        Ok(changes)
+    }
+
+    /// Replaces where the services are loaded from, the previous sources are
+    /// returned
+    pub fn set_sources(&mut self, sources: ServiceSources) -> ServiceSources {
+        std::mem::replace(&mut self.sources, sources)
+    }
+
+    /// Every problem of the service files, as in check-config
+    pub fn check(&self) -> String {
+        let (files, problems) = check::check_services(&self.sources);
+
+        match problems.is_empty() {
+            true => format!("No problems found in {files} files"),
+            false => problems.iter().fold(
+                format!("{} problems found in {files} files:\n", problems.len()),
+                |acc, problem| acc + &format!("{problem}\n"),
+            ),
+        }
+    }
+
+    /// The changes a reload would do, without applying them
+    pub fn plan(&self) -> Result<Vec<(String, ServiceChange)>, io::Error> {
+        Ok(self.diff(&load_services(&self.sources, &self.scaled)?))
+    }
+
+    /// Changes the numprocs of a service until its file changes it, or in the
+    /// file with persist. The jobs already there keep their configuration.
+    /// Returns the aliases added and the ones to remove, highest first
+    pub fn scale(
+        &mut self,
+        alias: &str,
+        numprocs: u16,
+        persist: bool,
+    ) -> Result<(Vec<String>, Vec<String>), io::Error> {
+        let base = self
+            .services
+            .get(alias)
+            .ok_or(io::Error::other(format!("Service not found: {alias}")))?;
+        let old_numprocs = base.numprocs;
+        let file_numprocs = self
+            .scaled
+            .get(alias)
+            .map_or(old_numprocs, |(file_numprocs, _)| *file_numprocs);
+
+        let previous = self.scaled.clone();
+        self.scaled
+            .insert(alias.to_string(), (file_numprocs, numprocs));
+        let new_services = load_services(&self.sources, &self.scaled)
+            .and_then(|new_services| match new_services.get(alias) {
+                Some(new) if new.numprocs == numprocs => Ok(new_services),
+                Some(_) => Err(io::Error::other(format!(
+                    "numprocs of {alias} changed in its file, reload first"
+                ))),
+                None => Err(io::Error::other(format!(
+                    "{alias} is no longer in its file, reload first"
+                ))),
+            })
+            .and_then(|new_services| match persist {
+                true => persist_numprocs(base, numprocs).map(|_| new_services),
+                false => Ok(new_services),
+            });
+        let mut new_services = match new_services {
+            Ok(new_services) => new_services,
+            Err(err) => {
+                self.scaled = previous;
+                return Err(err);
+            }
+        };
+        if persist {
+            self.scaled.remove(alias);
+        }
+
+        let old: Vec<String> = taskmeister::generate_alias_names(alias, old_numprocs).collect();
+        let new: Vec<String> = taskmeister::generate_alias_names(alias, numprocs).collect();
+
+        if let Some(base) = self.services.get_mut(alias) {
+            base.numprocs = numprocs;
+        }
+        let added: Vec<String> = new
+            .iter()
+            .filter(|instance| !old.contains(instance))
+            .cloned()
+            .collect();
+        for instance in &added {
+            if let Some(service) = new_services.remove(instance) {
+                self.services.insert(instance.clone(), service);
+            }
+        }
+        // Removed services are kept until their job is stopped
+        let removed = old
+            .into_iter()
+            .filter(|instance| !new.contains(instance))
+            .rev()
+            .collect();
+
+        Ok((added, removed))
+    }
+
+    fn diff(&self, new_services: &HashMap<String, Service>) -> Vec<(String, ServiceChange)> {
+        let mut changes: Vec<(String, ServiceChange)> = new_services
+            .iter()
+            .map(|(alias, new)| {
+                let change = match self.services.get(alias) {
+                    Some(old) => old.change_to(new),
+                    None => ServiceChange::Added(new.autostart),
+                };
+                (alias.clone(), change)
+            })
+            .chain(
+                self.services
+                    .keys()
+                    .filter(|alias| !new_services.contains_key(*alias))
+                    .map(|alias| (alias.clone(), ServiceChange::Removed)),
+            )
+            .collect();
+
+        changes.sort_by(|(alias1, _), (alias2, _)| alias1.cmp(alias2));
+        changes
     }
 
     pub fn get(&self, alias: &str) -> Option<&Service> {
@@ -117,26 +745,23 @@ impl Services {
     }
 }
 
-fn load_services(paths: &Vec<PathBuf>) -> Result<HashMap<String, Service>, io::Error> {
+fn load_services(
+    sources: &ServiceSources,
+    scaled: &HashMap<String, (u16, u16)>,
+) -> Result<HashMap<String, Service>, io::Error> {
     let mut services = HashMap::new();
 
-    for p in paths {
+    for p in &sources.paths {
         let p = dir_utils::expand_home_dir(p);
 
         if !p.exists() {
             return Err(io::Error::other(format!("Path not found: {p:?}")));
         }
 
-        dir_utils::walk_dir(p, &mut |closure_p| {
-            let Ok(mut service) = toml::from_str::<Service>(&fs::read_to_string(&closure_p)?)
-            else {
-                return Err(io::Error::other(format!(
-                    "Couldn't deserialize: {}",
-                    closure_p.display()
-                )));
-            };
-
-            service.file = closure_p;
+        for closure_p in service_files(&p, &sources.patterns)? {
+            let text = fs::read_to_string(&closure_p)?;
+            let file_services = parse_services(&closure_p, &text, sources)
+                .map_err(|problem| io::Error::other(format!("Couldn't deserialize: {problem}")))?;
 
             let mut insert_service = |alias: String, serv: Service| match services.entry(alias) {
                 Entry::Occupied(o) => {
@@ -153,19 +778,22 @@ fn load_services(paths: &Vec<PathBuf>) -> Result<HashMap<String, Service>, io::E
             };
 
             // If numprocs is > 1 insert virtual services in order to run multiple jobs
-            for (i, alias) in
-                taskmeister::generate_alias_names(&service.alias, service.numprocs).enumerate()
-            {
-                let mut service = service.clone();
-                service.alias = alias.clone();
-                if i > 0 {
-                    service.numprocs = 1;
+            for mut service in file_services {
+                // Scaled at runtime, until the file changes its numprocs
+                if let Some((file_numprocs, numprocs)) = scaled.get(&service.alias)
+                    && *file_numprocs == service.numprocs
+                {
+                    service.numprocs = *numprocs;
                 }
-                insert_service(alias, service)?;
-            }
 
-            Ok(())
-        })?;
+                let instances = service.instances().map_err(|(key, message)| {
+                    io::Error::other(Problem::at_key(&closure_p, &text, key, message).to_string())
+                })?;
+                for instance in instances {
+                    insert_service(instance.alias.clone(), instance)?;
+                }
+            }
+        }
     }
 
     Ok(services)
@@ -190,19 +818,271 @@ impl Service {
             });
         }
 
+        self.apply_env(&mut cmd);
         cmd.args(args)
             .stdout(Stdio::piped())
             .stdin(Stdio::piped())
             .stderr(Stdio::piped())
-            .envs(&self.env)
             .current_dir(dir_utils::expand_home_dir(&self.working_dir))
             .spawn()
     }
 
-    pub fn validate_exit_code(&self, exit_code: i32) -> bool {
-        for code in &self.exit_codes {
-            if exit_code == *code {
-                return true;
+    /// The jobs of the service: alias, alias.1 ... each one with its
+    /// environment built and ${VAR} expanded. Errors come with their key
+    pub fn instances(&self) -> Result<Vec<Service>, (&'static str, String)> {
+        taskmeister::generate_alias_names(&self.alias, self.numprocs)
+            .enumerate()
+            .map(|(instance, alias)| {
+                let mut service = self.clone();
+                service.alias = alias;
+                service.instance = instance;
+                if instance > 0 {
+                    service.numprocs = 1;
+                }
+                service.expand_vars(instance)?;
+                Ok(service)
+            })
+            .collect()
+    }
+
+    // The standard variables, then the env files, then env. What is expanded
+    // sees the variables before it and the server environment. The instance
+    // placeholders are replaced first in env, cmd, working_dir and the outputs
+    fn expand_vars(&mut self, instance: usize) -> Result<(), (&'static str, String)> {
+        let mut env = HashMap::from([
+            ("TASKMEISTER_ALIAS".to_string(), self.alias.clone()),
+            ("TASKMEISTER_INSTANCE".to_string(), instance.to_string()),
+            (
+                "TASKMEISTER_SERVER_PID".to_string(),
+                std::process::id().to_string(),
+            ),
+        ]);
+
+        let service_dir = self.file.parent().unwrap_or(Path::new("")).to_path_buf();
+        for env_file in &self.env_files {
+            let path =
+                interpolate(&env_file.to_string_lossy(), &env).map_err(|err| ("env_files", err))?;
+            let path = service_dir.join(dir_utils::expand_home_dir(Path::new(&path)));
+            let vars = read_env_file(&path)
+                .map_err(|err| ("env_files", format!("{}: {err}", path.display())))?;
+
+            for (name, value) in vars {
+                let value = interpolate(&value, &env).map_err(|err| ("env_files", err))?;
+                env.insert(name, value);
+            }
+        }
+
+        let before_env = env.clone();
+        for (name, value) in &self.env {
+            let value = expand(value, instance, &before_env).map_err(|err| ("env", err))?;
+            env.insert(name.clone(), value);
+        }
+
+        self.cmd = expand(&self.cmd, instance, &env).map_err(|err| ("cmd", err))?;
+        self.working_dir = PathBuf::from(
+            expand(&self.working_dir.to_string_lossy(), instance, &env)
+                .map_err(|err| ("working_dir", err))?,
+        );
+        self.stdout = expand(&self.stdout, instance, &env).map_err(|err| ("stdout", err))?;
+        self.stderr = expand(&self.stderr, instance, &env).map_err(|err| ("stderr", err))?;
+        self.env = env;
+
+        Ok(())
+    }
+
+    // All the server environment, or only inherit_env when cleared, and then
+    // the one of the service
+    fn apply_env(&self, cmd: &mut Command) {
+        if self.clear_env || !self.inherit_env.is_empty() {
+            cmd.env_clear();
+            for name in &self.inherit_env {
+                if let Ok(value) = std::env::var(name) {
+                    cmd.env(name, value);
+                }
+            }
+        }
+        cmd.envs(&self.env);
+    }
+
+    /// Problems that parse fine but would fail when the job starts, with the
+    /// key of each one
+    pub fn problems(&self) -> Vec<(&'static str, String)> {
+        let mut problems = Vec::new();
+        let working_dir = dir_utils::expand_home_dir(&self.working_dir);
+
+        if !working_dir.is_dir() {
+            problems.push((
+                "working_dir",
+                format!("{} is not a directory", working_dir.display()),
+            ));
+        }
+
+        match self.cmd.split_ascii_whitespace().next() {
+            Some(program) if self.resolve(program, &working_dir).is_none() => {
+                problems.push(("cmd", format!("{program} not found or not executable")))
+            }
+            Some(_) => (),
+            None => problems.push(("cmd", "No command provided".to_string())),
+        }
+
+        if self.numprocs == 0 {
+            problems.push(("numprocs", "No process would be started".to_string()));
+        }
+
+        for (key, target) in [("stdout", &self.stdout), ("stderr", &self.stderr)] {
+            if ["null", "logger", "syslog"].contains(&target.as_str()) {
+                continue;
+            }
+            let dir = match Path::new(target).parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            if !writable(dir) {
+                problems.push((
+                    key,
+                    format!("{} is not a writable directory", dir.display()),
+                ));
+            }
+        }
+
+        problems
+    }
+
+    // Where the program of the command is found, like the exec of the job
+    fn resolve(&self, program: &str, working_dir: &Path) -> Option<PathBuf> {
+        let executable = |path: &Path| {
+            fs::metadata(path)
+                .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        };
+
+        if program.contains('/') {
+            let path = working_dir.join(program);
+            return executable(&path).then_some(path);
+        }
+
+        let path_var = self
+            .env
+            .get("PATH")
+            .cloned()
+            .or_else(|| std::env::var("PATH").ok())
+            .unwrap_or_default();
+
+        path_var
+            .split(':')
+            .map(|dir| Path::new(dir).join(program))
+            .find(|path| executable(path))
+    }
+
+    // Command for hooks and stop steps, with the same environment and
+    // working directory as the job itself
+    pub fn helper_command(&self, cmd: &str) -> Result<Command, io::Error> {
+        let mut args = cmd.split_ascii_whitespace();
+
+        let mut cmd = Command::new(
+            args.next()
+                .ok_or(io::Error::other("No command provided!"))?,
+        );
+
+        self.apply_env(&mut cmd);
+        cmd.args(args)
+            .stdout(Stdio::null())
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .current_dir(dir_utils::expand_home_dir(&self.working_dir));
+
+        Ok(cmd)
+    }
+
+    // Restart when the process would be spawned differently, a signal can not
+    // hand it a new environment or working directory. Anything else is read
+    // from the service when needed so it applies live
+    fn change_to(&self, new: &Service) -> ServiceChange {
+        let fields = self.changed_fields(new);
+
+        if fields.is_empty() {
+            ServiceChange::Unchanged
+        } else if fields.iter().any(|field| SPAWN_FIELDS.contains(field)) {
+            ServiceChange::Restart(fields)
+        } else if new.can_reload() {
+            ServiceChange::Reload(fields)
+        } else {
+            ServiceChange::Update(fields)
+        }
+    }
+
+    fn changed_fields(&self, other: &Service) -> Vec<&'static str> {
+        [
+            ("cmd", self.cmd != other.cmd),
+            ("numprocs", self.numprocs != other.numprocs),
+            ("restart", self.restart != other.restart),
+            ("start_time", self.start_time != other.start_time),
+            ("stop_signal", self.stop_signal != other.stop_signal),
+            ("stop_wait", self.stop_wait != other.stop_wait),
+            ("stop_sequence", self.stop_sequence != other.stop_sequence),
+            ("reload_signal", self.reload_signal != other.reload_signal),
+            ("reload_cmd", self.reload_cmd != other.reload_cmd),
+            ("autostart", self.autostart != other.autostart),
+            ("exit_codes", self.exit_codes != other.exit_codes),
+            ("stdout", self.stdout != other.stdout),
+            ("stdin", self.stdin != other.stdin),
+            ("stderr", self.stderr != other.stderr),
+            ("output", self.output != other.output),
+            ("watchdog", self.watchdog != other.watchdog),
+            ("hooks", self.hooks != other.hooks),
+            ("env", self.env != other.env),
+            ("env_files", self.env_files != other.env_files),
+            ("clear_env", self.clear_env != other.clear_env),
+            ("inherit_env", self.inherit_env != other.inherit_env),
+            ("working_dir", self.working_dir != other.working_dir),
+            ("umask", self.umask != other.umask),
+        ]
+        .into_iter()
+        .filter_map(|(field, changed)| changed.then_some(field))
+        .collect()
+    }
+
+    /// The alias of the service of this job, without the instance
+    pub fn service_alias(&self) -> &str {
+        match self.instance {
+            0 => &self.alias,
+            instance => self
+                .alias
+                .strip_suffix(&format!(".{instance}"))
+                .unwrap_or(&self.alias),
+        }
+    }
+
+    pub fn can_reload(&self) -> bool {
+        self.reload_signal.is_some() || self.reload_cmd.is_some()
+    }
+
+    // The configured stop sequence, or a single step out of stop_signal and stop_wait
+    pub fn stop_steps(&self) -> Vec<StopStep> {
+        if !self.stop_sequence.is_empty() {
+            return self.stop_sequence.clone();
+        }
+
+        vec![StopStep {
+            action: StopAction::Signal {
+                signal: self.stop_signal,
+            },
+            wait: self.stop_wait,
+        }]
+    }
+
+    pub fn validate_exit_code(&self, reason: &ExitReason) -> bool {
+        for expected in &self.exit_codes {
+            match (expected, reason) {
+                (ExpectedExit::Code(code), ExitReason::Code(exit_code))
+                | (
+                    ExpectedExit::Signal(code),
+                    ExitReason::Signal {
+                        signal: exit_code, ..
+                    },
+                ) if code == exit_code => {
+                    return true;
+                }
+                _ => (),
             }
         }
         return false;
@@ -211,34 +1091,46 @@ impl Service {
 
 // UTILS
 
-fn signal_from_str(signal_string: &str) -> Option<i32> {
+const SIGNALS: [(&str, i32); 21] = [
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP),
+    ("ABRT", libc::SIGABRT),
+    ("BUS", libc::SIGBUS),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+];
+
+pub fn signal_from_str(signal_string: &str) -> Option<i32> {
     let name = signal_string.trim().to_ascii_uppercase();
     let name = name.strip_prefix("SIG").unwrap_or(&name);
 
-    match name {
-        "HUP" => Some(libc::SIGHUP),
-        "INT" => Some(libc::SIGINT),
-        "QUIT" => Some(libc::SIGQUIT),
-        "ILL" => Some(libc::SIGILL),
-        "TRAP" => Some(libc::SIGTRAP),
-        "ABRT" => Some(libc::SIGABRT),
-        "BUS" => Some(libc::SIGBUS),
-        "FPE" => Some(libc::SIGFPE),
-        "KILL" => Some(libc::SIGKILL),
-        "USR1" => Some(libc::SIGUSR1),
-        "SEGV" => Some(libc::SIGSEGV),
-        "USR2" => Some(libc::SIGUSR2),
-        "PIPE" => Some(libc::SIGPIPE),
-        "ALRM" => Some(libc::SIGALRM),
-        "TERM" => Some(libc::SIGTERM),
-        "CHLD" => Some(libc::SIGCHLD),
-        "CONT" => Some(libc::SIGCONT),
-        "STOP" => Some(libc::SIGSTOP),
-        "TSTP" => Some(libc::SIGTSTP),
-        "TTIN" => Some(libc::SIGTTIN),
-        "TTOU" => Some(libc::SIGTTOU),
-        _ => None,
-    }
+    SIGNALS
+        .iter()
+        .find(|(signal_name, _)| *signal_name == name)
+        .map(|(_, signal)| *signal)
+}
+
+// Name of the signal without the "SIG" prefix
+pub fn signal_to_str(signal: i32) -> Option<&'static str> {
+    SIGNALS
+        .iter()
+        .find(|(_, number)| *number == signal)
+        .map(|(name, _)| *name)
 }
 
 fn deserialize_signal<'de, D>(deserializer: D) -> Result<i32, D::Error>
@@ -250,3 +1142,481 @@ where
     signal_from_str(&string)
         .ok_or_else(|| de::Error::custom(format!("Invalid Signal name: {string}")))
 }
+
+fn expand(text: &str, instance: usize, vars: &HashMap<String, String>) -> Result<String, String> {
+    interpolate(&instance_placeholders(text, instance)?, vars)
+}
+
+// Replaces %i and {instance} with the instance number, the braces also take
+// arithmetic with integers: {instance + 8000}, {2 * instance + 1}. Braces
+// without instance in them are left as they are, and {{instance}} is a
+// literal {instance}
+fn instance_placeholders(text: &str, instance: usize) -> Result<String, String> {
+    let text = text.replace("%i", &instance.to_string());
+    let mut replaced = String::new();
+    let mut rest = text.as_str();
+
+    while let Some(start) = rest.find('{') {
+        let after = &rest[start + 1..];
+
+        if let Some(escaped) = after.strip_prefix('{') {
+            replaced.push_str(&rest[..start]);
+            match escaped.find("}}") {
+                Some(end) if escaped[..end].contains("instance") => {
+                    replaced.push_str(&format!("{{{}}}", &escaped[..end]));
+                    rest = &escaped[end + 2..];
+                }
+                _ => {
+                    replaced.push_str("{{");
+                    rest = escaped;
+                }
+            }
+            continue;
+        }
+
+        let Some(end) = after.find('}') else {
+            if after.contains("instance") {
+                return Err(format!("Unclosed {{ in: {text}"));
+            }
+            break;
+        };
+        let expression = &after[..end];
+
+        // ${NAME} is for interpolate
+        if !expression.contains("instance") || rest[..start].ends_with('$') {
+            replaced.push_str(&rest[..start + end + 2]);
+        } else {
+            let value = evaluate(expression, instance as i64)
+                .ok_or(format!("Invalid instance expression: {{{expression}}}"))?;
+            replaced.push_str(&rest[..start]);
+            replaced.push_str(&value.to_string());
+        }
+        rest = &after[end + 1..];
+    }
+    replaced.push_str(rest);
+
+    Ok(replaced)
+}
+
+// Integers and instance with + - * / % and the usual precedence, without
+// parentheses, as in 8000 + instance * 2. None on overflow or division by 0
+fn evaluate(expression: &str, instance: i64) -> Option<i64> {
+    let expression: String = expression.split_whitespace().collect();
+    let mut total = 0i64;
+
+    // A leading sign: -instance
+    let (mut sign, mut rest) = match expression.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, expression.as_str()),
+    };
+
+    loop {
+        let end = rest.find(['+', '-']).unwrap_or(rest.len());
+        total = total.checked_add(evaluate_term(&rest[..end], instance)?.checked_mul(sign)?)?;
+
+        match rest[end..].chars().next() {
+            Some(operator) => {
+                sign = if operator == '+' { 1 } else { -1 };
+                rest = &rest[end + 1..];
+            }
+            None => return Some(total),
+        }
+    }
+}
+
+// Factors with * / %, from left to right
+fn evaluate_term(term: &str, instance: i64) -> Option<i64> {
+    let mut operators = std::iter::once('*').chain(term.chars().filter(|c| "*/%".contains(*c)));
+
+    term.split(['*', '/', '%']).try_fold(1i64, |value, factor| {
+        let factor = match factor {
+            "instance" => instance,
+            number if number.bytes().all(|byte| byte.is_ascii_digit()) => number.parse().ok()?,
+            _ => return None,
+        };
+        match operators.next()? {
+            '*' => value.checked_mul(factor),
+            '/' => value.checked_div(factor),
+            _ => value.checked_rem(factor),
+        }
+    })
+}
+
+// Writes numprocs in the last drop-in setting it, or else in the service file:
+// in its [[service]] entry or at the top level
+fn persist_numprocs(service: &Service, numprocs: u16) -> Result<(), io::Error> {
+    let dropin = service.dropins.iter().rev().find(|dropin| {
+        fs::read_to_string(dropin)
+            .ok()
+            .and_then(|text| text.parse::<toml::Table>().ok())
+            .is_some_and(|table| table.contains_key("numprocs"))
+    });
+    let file = dropin.unwrap_or(&service.file);
+
+    let text = fs::read_to_string(file)?;
+    fs::write(file, set_numprocs(&text, &service.alias, numprocs))
+}
+
+fn set_numprocs(text: &str, alias: &str, numprocs: u16) -> String {
+    let mut lines: Vec<String> = text.lines().map(String::from).collect();
+    let is_key = |line: &str, key: &str| {
+        line.trim_start()
+            .strip_prefix(key)
+            .is_some_and(|rest| rest.trim_start().starts_with('='))
+    };
+    let is_header = |line: &str| line.trim_start().starts_with('[');
+
+    // The entry goes from its alias back to its header, and on to the next one
+    let alias_line = lines.iter().position(|line| {
+        is_key(line, "alias")
+            && (line.contains(&format!("\"{alias}\"")) || line.contains(&format!("'{alias}'")))
+    });
+    let start = alias_line
+        .and_then(|alias_line| lines[..alias_line].iter().rposition(|line| is_header(line)))
+        .map_or(0, |header| header + 1);
+    let end = lines[start..]
+        .iter()
+        .position(|line| is_header(line))
+        .map_or(lines.len(), |header| start + header);
+
+    let setting = format!("numprocs = {numprocs}");
+    match lines[start..end]
+        .iter()
+        .position(|line| is_key(line, "numprocs"))
+    {
+        Some(line) => lines[start + line] = setting,
+        None => lines.insert(alias_line.map_or(start, |line| line + 1), setting),
+    }
+
+    lines.join("\n") + "\n"
+}
+
+// Expands ${NAME} with the variables given, or else the server environment.
+// $$ is a literal $
+fn interpolate(text: &str, vars: &HashMap<String, String>) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+
+        if let Some(after) = after.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        let Some(after) = after.strip_prefix('{') else {
+            expanded.push('$');
+            rest = after;
+            continue;
+        };
+
+        let end = after.find('}').ok_or(format!("Unclosed ${{ in: {text}"))?;
+        let name = &after[..end];
+
+        let value = vars
+            .get(name)
+            .cloned()
+            .or_else(|| std::env::var(name).ok())
+            .ok_or(format!("Undefined variable ${{{name}}}"))?;
+        expanded.push_str(&value);
+        rest = &after[end + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+fn read_env_file(path: &Path) -> Result<Vec<(String, String)>, io::Error> {
+    parse_env_file(&fs::read_to_string(path)?)
+}
+
+// KEY=VALUE lines of a dotenv file, "export" and quotes around the value are
+// allowed, blank lines and # comments skipped
+fn parse_env_file(text: &str) -> Result<Vec<(String, String)>, io::Error> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (name, value) = line
+                .split_once('=')
+                .filter(|(name, _)| !name.trim().is_empty())
+                .ok_or(io::Error::other(format!("Not a KEY=VALUE line: {line}")))?;
+            let value = value.trim();
+            let value = ['"', '\'']
+                .iter()
+                .find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote))
+                .unwrap_or(value);
+
+            Ok((name.trim().to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+fn writable(dir: &Path) -> bool {
+    let Ok(dir) = std::ffi::CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    unsafe { libc::access(dir.as_ptr(), libc::W_OK) == 0 }
+}
+
+fn deserialize_option_signal<'de, D>(deserializer: D) -> Result<Option<i32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|string| {
+            signal_from_str(&string)
+                .ok_or_else(|| de::Error::custom(format!("Invalid Signal name: {string}")))
+        })
+        .transpose()
+}
+
+fn serialize_option_signal<S>(signal: &Option<i32>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match signal {
+        Some(signal) => serialize_signal(signal, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_signal<S>(signal: &i32, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match signal_to_str(*signal) {
+        Some(name) => serializer.serialize_str(name),
+        None => serializer.serialize_i32(*signal),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn change_classification() {
+        let old = Service {
+            reload_signal: Some(libc::SIGHUP),
+            ..Service::default()
+        };
+
+        let new = Service {
+            exit_codes: vec![ExpectedExit::Code(2)],
+            ..old.clone()
+        };
+        assert_eq!(
+            old.change_to(&new),
+            ServiceChange::Reload(vec!["exit_codes"])
+        );
+
+        // The running process can not take a new environment
+        let new = Service {
+            env: vars(&[("A", "1")]),
+            ..old.clone()
+        };
+        assert_eq!(old.change_to(&new), ServiceChange::Restart(vec!["env"]));
+
+        let new = Service {
+            env: vars(&[("A", "1")]),
+            cmd: "other".to_string(),
+            ..old.clone()
+        };
+        assert_eq!(
+            old.change_to(&new),
+            ServiceChange::Restart(vec!["cmd", "env"])
+        );
+
+        // Without a way to reload, it only applies live
+        let old = Service::default();
+        let new = Service {
+            exit_codes: vec![ExpectedExit::Code(2)],
+            ..old.clone()
+        };
+        assert_eq!(
+            old.change_to(&new),
+            ServiceChange::Update(vec!["exit_codes"])
+        );
+        assert_eq!(old.change_to(&old.clone()), ServiceChange::Unchanged);
+    }
+
+    #[test]
+    fn watchdog_cpu_window_default() {
+        let watchdog: Watchdog = toml::from_str("max_cpu = 90.0").unwrap();
+        assert_eq!(watchdog.cpu_window, 10);
+
+        let watchdog: Watchdog = toml::from_str("max_cpu = 90.0\ncpu_window = 0").unwrap();
+        assert_eq!(watchdog.cpu_window, 0);
+    }
+
+    #[test]
+    fn instance_placeholders_replaced() {
+        assert_eq!(instance_placeholders("app-%i.log", 2).unwrap(), "app-2.log");
+        assert_eq!(instance_placeholders("{instance}", 3).unwrap(), "3");
+        assert_eq!(
+            instance_placeholders("--port={instance + 8000} --id=%i", 1).unwrap(),
+            "--port=8001 --id=1"
+        );
+    }
+
+    #[test]
+    fn instance_placeholders_left_alone() {
+        // For interpolate
+        assert_eq!(
+            instance_placeholders("${instance}", 1).unwrap(),
+            "${instance}"
+        );
+        assert_eq!(
+            instance_placeholders("${HOME}/{x}", 1).unwrap(),
+            "${HOME}/{x}"
+        );
+        // Braces without instance, as in JSON arguments
+        assert_eq!(
+            instance_placeholders("{\"a\": {\"b\": 1}}", 1).unwrap(),
+            "{\"a\": {\"b\": 1}}"
+        );
+        assert_eq!(
+            instance_placeholders("{{\"a\": 1}}", 1).unwrap(),
+            "{{\"a\": 1}}"
+        );
+        assert_eq!(instance_placeholders("a}b{c", 1).unwrap(), "a}b{c");
+    }
+
+    #[test]
+    fn instance_placeholders_escaped() {
+        assert_eq!(
+            instance_placeholders("{{instance}}", 1).unwrap(),
+            "{instance}"
+        );
+        assert_eq!(
+            instance_placeholders("{{instance + 1}}-{instance}", 4).unwrap(),
+            "{instance + 1}-4"
+        );
+    }
+
+    #[test]
+    fn instance_placeholders_errors() {
+        assert!(instance_placeholders("{instance", 1).is_err());
+        assert!(instance_placeholders("--port={instance + 1", 1).is_err());
+        assert!(instance_placeholders("{instance +}", 1).is_err());
+        assert!(instance_placeholders("{instance / 0}", 1).is_err());
+    }
+
+    #[test]
+    fn evaluate_precedence() {
+        assert_eq!(evaluate("instance", 5), Some(5));
+        assert_eq!(evaluate("8000 + instance", 5), Some(8005));
+        assert_eq!(evaluate("2 + instance * 3", 5), Some(17));
+        assert_eq!(evaluate("instance * 3 + 2", 5), Some(17));
+        assert_eq!(evaluate("10 - instance - 2", 5), Some(3));
+        assert_eq!(evaluate("-instance + 1", 5), Some(-4));
+        assert_eq!(evaluate("100 - instance * 2 * 3", 5), Some(70));
+        assert_eq!(evaluate("instance / 2", 5), Some(2));
+        assert_eq!(evaluate("instance % 2", 5), Some(1));
+        assert_eq!(evaluate("1 + instance * 6 / 4 % 5", 5), Some(3));
+    }
+
+    #[test]
+    fn evaluate_errors() {
+        assert_eq!(evaluate("instance / 0", 5), None);
+        assert_eq!(evaluate("instance % 0", 5), None);
+        assert_eq!(evaluate("instance +", 5), None);
+        assert_eq!(evaluate("instance * * 2", 5), None);
+        assert_eq!(evaluate("--instance", 5), None);
+        assert_eq!(evaluate("(instance + 1) * 2", 5), None);
+        assert_eq!(evaluate("instance * x", 5), None);
+        assert_eq!(evaluate("9223372036854775807 + instance", 5), None);
+        assert_eq!(evaluate("", 5), None);
+    }
+
+    #[test]
+    fn interpolate_variables() {
+        let vars = vars(&[("A", "1"), ("B", "two")]);
+
+        assert_eq!(interpolate("${A}-${B}", &vars).unwrap(), "1-two");
+        assert_eq!(interpolate("no variables", &vars).unwrap(), "no variables");
+        assert_eq!(interpolate("${A}${A}", &vars).unwrap(), "11");
+        // Only the braced form is expanded
+        assert_eq!(interpolate("$A and $", &vars).unwrap(), "$A and $");
+    }
+
+    #[test]
+    fn interpolate_server_environment() {
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(interpolate("${PATH}", &HashMap::new()).unwrap(), path);
+        // The given variables win over the server ones
+        assert_eq!(
+            interpolate("${PATH}", &vars(&[("PATH", "/x")])).unwrap(),
+            "/x"
+        );
+    }
+
+    #[test]
+    fn interpolate_escapes() {
+        let vars = vars(&[("A", "1")]);
+
+        assert_eq!(interpolate("$$", &vars).unwrap(), "$");
+        assert_eq!(interpolate("$${A}", &vars).unwrap(), "${A}");
+        assert_eq!(interpolate("$$$${A}", &vars).unwrap(), "$${A}");
+        assert_eq!(interpolate("$$${A}", &vars).unwrap(), "$1");
+    }
+
+    #[test]
+    fn interpolate_errors() {
+        assert_eq!(
+            interpolate("${TASKMEISTER_TEST_UNDEFINED}", &HashMap::new()).unwrap_err(),
+            "Undefined variable ${TASKMEISTER_TEST_UNDEFINED}"
+        );
+        assert!(interpolate("${A", &vars(&[("A", "1")])).is_err());
+    }
+
+    #[test]
+    fn env_file_lines() {
+        let text = "\n# comment\n  # indented comment\nA=1\nexport B = two\n  C=x=y  \n";
+
+        assert_eq!(
+            parse_env_file(text).unwrap(),
+            vec![
+                ("A".to_string(), "1".to_string()),
+                ("B".to_string(), "two".to_string()),
+                ("C".to_string(), "x=y".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn env_file_quotes() {
+        let text = "A=\"a b\"\nB='c d'\nC=\"unmatched'\nD=\"\nE=\"\"\nF=\"${X}\"";
+
+        assert_eq!(
+            parse_env_file(text).unwrap(),
+            vec![
+                ("A".to_string(), "a b".to_string()),
+                ("B".to_string(), "c d".to_string()),
+                ("C".to_string(), "\"unmatched'".to_string()),
+                ("D".to_string(), "\"".to_string()),
+                ("E".to_string(), "".to_string()),
+                // Interpolated later, with the variables before it
+                ("F".to_string(), "${X}".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn env_file_errors() {
+        assert!(parse_env_file("A=1\nnot a variable").is_err());
+        assert!(parse_env_file("=1").is_err());
+        assert!(parse_env_file("export =1").is_err());
+    }
+}
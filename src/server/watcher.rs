@@ -1,17 +1,24 @@
 use std::{
     collections::HashMap,
-    io,
+    io, mem,
+    os::unix::process::ExitStatusExt,
     process::{Child, ExitStatus},
-    sync::{Arc, Mutex, mpsc::Sender},
+    sync::{Arc, Mutex},
     thread,
     time::{Duration, Instant},
 };
 
 use logger::{LogLevel, Logger};
 
-use crate::events::JobEvent;
-use crate::jobs::JobStatus;
-use crate::orchestrate::OrchestratorMsg;
+use crate::events::{JobEvent, WatchdogEvent};
+use crate::history::RunUsage;
+use crate::jobs::{ExitReason, JobStatus};
+use crate::orchestrate::{OrchestratorMsg, OrchestratorSender};
+use crate::resources::{ProcUsage, Sampler};
+use crate::service::Watchdog;
+
+// How often the resources of running jobs are read from /proc
+const SAMPLE_PERIOD: Duration = Duration::from_secs(1);
 
 #[derive(Debug)]
 pub struct WatchedTimeout {
@@ -39,43 +46,141 @@ impl WatchedTimeout {
     }
 }
 
+#[derive(Debug)]
+pub struct WatchedLimits {
+    limits: Watchdog,
+    cpu_over_since: Option<Instant>,
+    tripped: bool, // Only reported once, the job is being restarted
+}
+
+impl WatchedLimits {
+    pub fn new(limits: Watchdog) -> WatchedLimits {
+        WatchedLimits {
+            limits,
+            cpu_over_since: None,
+            tripped: false,
+        }
+    }
+
+    // New limits from a config reload, what was measured so far is kept
+    pub fn set(&mut self, limits: Watchdog) {
+        self.limits = limits;
+    }
+
+    // Returns the reason the first time a limit is exceeded
+    fn check(&mut self, usage: &ProcUsage) -> Option<String> {
+        if self.tripped {
+            return None;
+        }
+
+        if usage.cpu_percent > self.limits.max_cpu.unwrap_or(f64::INFINITY) {
+            self.cpu_over_since.get_or_insert_with(Instant::now);
+        } else {
+            self.cpu_over_since = None;
+        }
+
+        let reason = if let Some(max_rss) = self.limits.max_rss
+            && usage.rss > max_rss
+        {
+            Some(format!(
+                "RSS {} bytes over the limit of {max_rss}",
+                usage.rss
+            ))
+        } else if let Some(max_fds) = self.limits.max_fds
+            && usage.fds > max_fds
+        {
+            Some(format!(
+                "{} open fds over the limit of {max_fds}",
+                usage.fds
+            ))
+        } else if let (Some(max_cpu), Some(since)) = (self.limits.max_cpu, self.cpu_over_since)
+            && since.elapsed() >= Duration::from_secs(self.limits.cpu_window)
+        {
+            Some(format!(
+                "CPU over {max_cpu:.1}% for {}s, now {:.1}%",
+                since.elapsed().as_secs(),
+                usage.cpu_percent
+            ))
+        } else {
+            None
+        };
+
+        self.tripped = reason.is_some();
+        reason
+    }
+}
+
 pub struct Watched {
     pub process: Child,
     pub timeout: WatchedTimeout,
     pub previous_status: JobStatus,
+    pub exited: Option<(ExitStatus, RunUsage)>, // Kept since a reaped process can't be waited again
+    pub sampler: Sampler,
+    pub limits: WatchedLimits,
 }
 
 pub fn watch(
     watched_jobs: Arc<Mutex<HashMap<String, Vec<Watched>>>>,
-    tx_events: Sender<OrchestratorMsg>,
+    tx_events: OrchestratorSender,
     period: Duration,
     logger: Logger,
 ) {
+    let mut last_sample = Instant::now();
+
     // TODO: Handle clean exit from this inifite loop
     loop {
+        let sample = last_sample.elapsed() >= SAMPLE_PERIOD;
+        if sample {
+            last_sample = Instant::now();
+        }
+
         for (alias, jobs) in watched_jobs.lock().unwrap().iter_mut() {
-            let event_sender = |event| {
+            let event_sender = |event, usage| {
                 if let Err(e) = tx_events.send(OrchestratorMsg::Event(JobEvent {
                     alias: alias.clone(),
                     status: event,
+                    usage,
                 })) {
                     logger::error!(logger, "Watcher send event: {e}");
                 }
             };
 
             for job in jobs {
+                // A process that exited may still be a zombie, there is nothing to sample
+                if sample && job.exited.is_none() {
+                    let pid = job.process.id();
+                    match job.sampler.sample(pid) {
+                        Ok(usage) => {
+                            if let Some(reason) = job.limits.check(usage) {
+                                let event = OrchestratorMsg::Watchdog(WatchdogEvent {
+                                    alias: alias.clone(),
+                                    pid,
+                                    reason,
+                                });
+                                if let Err(e) = tx_events.send(event) {
+                                    logger::error!(logger, "Watcher send watchdog event: {e}");
+                                }
+                            }
+                        }
+                        Err(_) => job.sampler.usage = None,
+                    }
+                }
+
                 if job.timeout.has_timed_out() {
                     if job.previous_status != JobStatus::TimedOut {
                         job.previous_status = JobStatus::TimedOut;
-                        event_sender(JobStatus::TimedOut);
+                        event_sender(JobStatus::TimedOut, None);
                     }
                     continue;
                 }
 
-                let new_status = exit_status_to_job_status(job.process.try_wait());
+                let new_status = exit_status_to_job_status(try_wait(job));
                 if new_status != job.previous_status {
                     job.previous_status = new_status.clone();
-                    event_sender(new_status);
+                    event_sender(
+                        new_status,
+                        job.exited.as_ref().map(|(_, usage)| usage.clone()),
+                    );
                     continue;
                 }
             }
@@ -84,12 +189,47 @@ pub fn watch(
     }
 }
 
+// Same as Child::try_wait but through wait4, to get the resources used by the process
+fn try_wait(job: &mut Watched) -> io::Result<Option<ExitStatus>> {
+    if job.exited.is_none() {
+        let mut status = 0;
+        let mut rusage: libc::rusage = unsafe { mem::zeroed() };
+
+        match unsafe {
+            libc::wait4(
+                job.process.id() as i32,
+                &mut status,
+                libc::WNOHANG,
+                &mut rusage,
+            )
+        } {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => return Ok(None),
+            _ => job.exited = Some((ExitStatus::from_raw(status), RunUsage::from(rusage))),
+        }
+    }
+
+    Ok(job.exited.as_ref().map(|(status, _)| *status))
+}
+
 fn exit_status_to_job_status(status: io::Result<Option<ExitStatus>>) -> JobStatus {
     match status {
         Ok(result) => match result {
-            Some(exit_status) => JobStatus::Finished(*exit_status.code().get_or_insert(0)),
+            Some(exit_status) => JobStatus::Finished(exit_status_to_reason(exit_status)),
             None => JobStatus::Running(false),
         },
         Err(_) => JobStatus::TimedOut,
     }
 }
+
+// Without an exit code the process was terminated by a signal
+fn exit_status_to_reason(exit_status: ExitStatus) -> ExitReason {
+    match (exit_status.code(), exit_status.signal()) {
+        (Some(code), _) => ExitReason::Code(code),
+        (None, Some(signal)) => ExitReason::Signal {
+            signal,
+            core_dumped: exit_status.core_dumped(),
+        },
+        (None, None) => ExitReason::Code(0),
+    }
+}